@@ -0,0 +1,280 @@
+use bevy::prelude::*;
+
+use crate::{
+    enemy::{spawn_enemy_of_type, Enemy, EnemyType},
+    grid::{ChangeAllegianceEvent, GridLayout, TileType},
+    health::Health,
+    loading::FontAssets,
+    GameState,
+};
+
+pub struct BossPlugin;
+
+impl Plugin for BossPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_exit(GameState::Loading).with_system(create_boss_bar_ui));
+        app.add_event::<BossPhaseEvent>()
+            .add_system(advance_boss_phases)
+            .add_system(update_boss_bar.after(advance_boss_phases));
+    }
+}
+
+/// What changes when a boss drops into a new phase.
+#[derive(Clone, Debug)]
+pub struct BossPhase {
+    // starts once health is at or below this fraction of max
+    pub health_fraction: f32,
+    pub speed: Option<f32>,
+    // spawned in a ring around the boss
+    pub summon: Option<(EnemyType, u32)>,
+    pub shield: Option<u32>,
+    // turns tiles in this range Hostile
+    pub hostile_range: Option<u32>,
+}
+
+impl BossPhase {
+    fn at(health_fraction: f32) -> Self {
+        BossPhase {
+            health_fraction,
+            speed: None,
+            summon: None,
+            shield: None,
+            hostile_range: None,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Boss {
+    pub name: &'static str,
+    // phases after the first, in the order they happen
+    phases: Vec<BossPhase>,
+    // 0 until the first entry in phases starts
+    current_phase: usize,
+}
+
+impl Boss {
+    pub fn current_phase(&self) -> usize {
+        self.current_phase
+    }
+
+    pub fn phase_count(&self) -> usize {
+        self.phases.len() + 1
+    }
+}
+
+pub fn boss_for(enemy_type: EnemyType) -> Option<Boss> {
+    match enemy_type {
+        EnemyType::Warlord => Some(Boss {
+            name: "Warlord",
+            phases: vec![
+                BossPhase {
+                    summon: Some((EnemyType::Swarmer, 6)),
+                    hostile_range: Some(2),
+                    ..BossPhase::at(0.75)
+                },
+                BossPhase {
+                    shield: Some(20),
+                    hostile_range: Some(3),
+                    ..BossPhase::at(0.5)
+                },
+                BossPhase {
+                    speed: Some(30.0),
+                    summon: Some((EnemyType::Swarmer, 10)),
+                    ..BossPhase::at(0.25)
+                },
+            ],
+            current_phase: 0,
+        }),
+        _ => None,
+    }
+}
+
+/// Sent when a boss moves into a new phase.
+pub struct BossPhaseEvent {
+    pub boss: Entity,
+    // 0 is the phase it spawns in
+    pub phase: usize,
+}
+
+fn advance_boss_phases(
+    mut commands: Commands,
+    mut q_bosses: Query<(Entity, &Transform, &mut Boss, &mut Enemy, &mut Health), Changed<Health>>,
+    mut ev_phase: EventWriter<BossPhaseEvent>,
+    mut ev_allegiance: EventWriter<ChangeAllegianceEvent>,
+    layout: Res<GridLayout>,
+) {
+    for (entity, trans, mut boss, mut enemy, mut health) in &mut q_bosses {
+        if health.is_dead() {
+            continue;
+        }
+
+        // a big hit can skip through more than one phase
+        while let Some(phase) = boss.phases.get(boss.current_phase).cloned() {
+            if health.fraction() > phase.health_fraction {
+                break;
+            }
+            boss.current_phase += 1;
+
+            let pos = trans.translation;
+            if let Some(speed) = phase.speed {
+                enemy.set_speed(speed);
+            }
+            if let Some((enemy_type, count)) = phase.summon {
+                for i in 0..count {
+                    let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                    let offset = Vec2::new(angle.cos(), angle.sin()) * (enemy.radius() + 8.0);
+                    spawn_enemy_of_type(&mut commands, enemy_type, pos + offset.extend(0.0));
+                }
+            }
+            if let Some(shield) = phase.shield {
+                health.add_shield(shield);
+            }
+            if let Some(range) = phase.hostile_range {
+                ev_allegiance.send(ChangeAllegianceEvent {
+                    center_coords: layout.coords(pos.truncate()),
+                    range,
+                    to: Some(TileType::Hostile),
+                });
+            }
+
+            ev_phase.send(BossPhaseEvent {
+                boss: entity,
+                phase: boss.current_phase,
+            });
+        }
+    }
+}
+
+#[derive(Component)]
+struct BossBarUI;
+
+#[derive(Component)]
+struct BossBarFill;
+
+#[derive(Component)]
+struct BossBarText;
+
+fn create_boss_bar_ui(mut commands: Commands, fonts: Res<FontAssets>) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                // top middle of the screen
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Percent(25.0),
+                    top: Val::Px(8.0),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(50.0), Val::Px(24.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                // hidden until a boss shows up
+                display: Display::None,
+                ..default()
+            },
+            color: Color::BLACK.into(),
+            ..default()
+        })
+        .insert(BossBarUI)
+        .with_children(|root| {
+            root.spawn_bundle(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        left: Val::Px(0.0),
+                        top: Val::Px(0.0),
+                        ..default()
+                    },
+                    size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                    ..default()
+                },
+                color: Color::CRIMSON.into(),
+                ..default()
+            })
+            .insert(BossBarFill);
+            root.spawn_bundle(TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: fonts.fira_sans.clone(),
+                    font_size: 18.0,
+                    color: Color::rgb(0.9, 0.9, 0.9),
+                },
+            ))
+            .insert(BossBarText);
+        });
+}
+
+fn update_boss_bar(
+    q_bosses: Query<(Entity, &Boss, &Health)>,
+    mut q_root: Query<&mut Style, (With<BossBarUI>, Without<BossBarFill>)>,
+    mut q_fill: Query<(&mut Style, &mut UiColor), (With<BossBarFill>, Without<BossBarUI>)>,
+    mut q_text: Query<&mut Text, With<BossBarText>>,
+    mut ev_phase: EventReader<BossPhaseEvent>,
+    // the boss and phase being announced, until the timer runs out
+    mut announce: Local<Option<(Entity, usize, Timer)>>,
+    time: Res<Time>,
+) {
+    // only the first boss gets the bar
+    let boss = q_bosses.iter().find(|(_, _, health)| !health.is_dead());
+
+    let display = if boss.is_some() {
+        Display::Flex
+    } else {
+        Display::None
+    };
+    for mut style in &mut q_root {
+        if style.display != display {
+            style.display = display;
+        }
+    }
+
+    let (entity, boss, health) = match boss {
+        Some(boss) => boss,
+        None => return,
+    };
+
+    for ev in ev_phase.iter().filter(|ev| ev.boss == entity) {
+        *announce = Some((ev.boss, ev.phase, Timer::from_seconds(1.0, false)));
+    }
+    let announcing = match &mut *announce {
+        Some((boss, phase, timer)) if *boss == entity => {
+            timer.tick(time.delta());
+            (!timer.finished()).then_some(*phase)
+        }
+        _ => None,
+    };
+
+    // the bar flashes white while a new phase is announced
+    let width = Val::Percent(health.fraction() * 100.0);
+    let fill_colour = if announcing.is_some() {
+        Color::WHITE
+    } else {
+        Color::CRIMSON
+    };
+    for (mut style, mut colour) in &mut q_fill {
+        if style.size.width != width {
+            style.size.width = width;
+        }
+        if colour.0 != fill_colour {
+            colour.0 = fill_colour;
+        }
+    }
+
+    let label = match announcing {
+        Some(phase) => format!("{} enters phase {}!", boss.name, phase + 1),
+        None => format!(
+            "{}  {}/{}  phase {}/{}",
+            boss.name,
+            health.current(),
+            health.max(),
+            boss.current_phase() + 1,
+            boss.phase_count()
+        ),
+    };
+    for mut text in &mut q_text {
+        if text.sections[0].value != label {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
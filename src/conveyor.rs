@@ -0,0 +1,191 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_prototype_lyon::prelude::{shapes, DrawMode, FillMode, GeometryBuilder};
+
+use crate::{
+    grid::{ClearSelectionsEvent, Grid, GridLayout, Selection, Tile, TileType, STRUCTURE_COST},
+    resource_container::{Resource, ResourceKind, StorageRules},
+};
+
+pub struct ConveyorPlugin;
+
+impl Plugin for ConveyorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(place_conveyors.before(crate::grid::clear_selection))
+            .add_system(run_conveyors);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConveyorDir {
+    Up,
+    Right,
+    Down,
+    Left,
+}
+
+impl ConveyorDir {
+    fn offset(&self) -> (i32, i32) {
+        match self {
+            ConveyorDir::Up => (0, 1),
+            ConveyorDir::Right => (1, 0),
+            ConveyorDir::Down => (0, -1),
+            ConveyorDir::Left => (-1, 0),
+        }
+    }
+
+    fn clockwise(&self) -> Self {
+        match self {
+            ConveyorDir::Up => ConveyorDir::Right,
+            ConveyorDir::Right => ConveyorDir::Down,
+            ConveyorDir::Down => ConveyorDir::Left,
+            ConveyorDir::Left => ConveyorDir::Up,
+        }
+    }
+
+    // the arrow points right with no rotation
+    fn rotation(&self) -> Quat {
+        let quarter_turns = match self {
+            ConveyorDir::Right => 0.0,
+            ConveyorDir::Up => 1.0,
+            ConveyorDir::Left => 2.0,
+            ConveyorDir::Down => 3.0,
+        };
+        Quat::from_rotation_z(quarter_turns * FRAC_PI_2)
+    }
+}
+
+/// Moves one resource at a time to the next tile in `dir`.
+/// Doesn't change the tile's cost, so enemies walk over it like open ground.
+#[derive(Component)]
+pub struct Conveyor {
+    dir: ConveyorDir,
+    timer: Timer,
+    arrow: Entity,
+}
+
+#[derive(Component)]
+struct ConveyorArrow;
+
+fn place_conveyors(
+    mut commands: Commands,
+    mut q_selection: Query<(Entity, &Tile, Option<&mut Conveyor>), With<Selection>>,
+    mut q_arrows: Query<&mut Transform, With<ConveyorArrow>>,
+    keyboard: Res<Input<KeyCode>>,
+    mut ev_clear: EventWriter<ClearSelectionsEvent>,
+    layout: Res<GridLayout>,
+) {
+    if !keyboard.just_pressed(KeyCode::C) {
+        return;
+    }
+    ev_clear.send(ClearSelectionsEvent);
+    for (ent, tile, conveyor) in q_selection.iter_mut() {
+        match conveyor {
+            Some(mut conveyor) => {
+                // placing again turns it
+                conveyor.dir = conveyor.dir.clockwise();
+                if let Ok(mut trans) = q_arrows.get_mut(conveyor.arrow) {
+                    trans.rotation = conveyor.dir.rotation();
+                }
+            }
+            None => {
+                // only on our own open ground
+                if !matches!(tile.tile_type, TileType::Friendly) || tile.cost >= STRUCTURE_COST {
+                    continue;
+                }
+                let dir = ConveyorDir::Right;
+                // in tiles, pointing right
+                let size = layout.tile_size;
+                let arrow = shapes::Polygon {
+                    points: vec![
+                        Vec2::new(-0.25, -0.25) * size,
+                        Vec2::new(0.3125, 0.0) * size,
+                        Vec2::new(-0.25, 0.25) * size,
+                    ],
+                    closed: true,
+                };
+                let arrow = commands
+                    .spawn_bundle(GeometryBuilder::build_as(
+                        &arrow,
+                        DrawMode::Fill(FillMode::color(Color::rgba(0.2, 0.2, 0.2, 0.6))),
+                        // under the coins
+                        Transform {
+                            translation: Vec3::new(0.0, 0.0, 0.05),
+                            rotation: dir.rotation(),
+                            ..default()
+                        },
+                    ))
+                    .insert(ConveyorArrow)
+                    .id();
+                commands.entity(ent).add_child(arrow).insert(Conveyor {
+                    dir,
+                    timer: Timer::from_seconds(1.0, true),
+                    arrow,
+                });
+            }
+        }
+    }
+}
+
+fn run_conveyors(
+    mut q_conveyors: Query<(Entity, &Tile, &mut Conveyor)>,
+    mut q_tiles: Query<(&Tile, &mut Resource)>,
+    grid: Res<Grid>,
+    rules: Res<StorageRules>,
+    time: Res<Time>,
+) {
+    // (from, to)
+    let mut moves = Vec::new();
+    for (entity, tile, mut conveyor) in &mut q_conveyors {
+        if !conveyor.timer.tick(time.delta()).just_finished() {
+            continue;
+        }
+        let (dx, dy) = conveyor.dir.offset();
+        let x = tile.coords.x as i32 + dx;
+        let y = tile.coords.y as i32 + dy;
+        if x < 0 || y < 0 {
+            continue;
+        }
+        if let Some(next) = grid.get_xy(x as usize, y as usize) {
+            moves.push((entity, next));
+        }
+    }
+
+    // what each tile had before anything moved.
+    // stops a unit riding down a whole line of conveyors in one tick
+    let mut available: HashMap<Entity, Resource> = HashMap::default();
+    for &(from, _) in &moves {
+        if let Ok((_, res)) = q_tiles.get(from) {
+            available.insert(from, *res);
+        }
+    }
+
+    for (from, to) in moves {
+        let mut v = match q_tiles.get_many_mut([from, to]) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        // can't carry things into or out of land we don't own
+        if !matches!(v[0].0.tile_type, TileType::Friendly)
+            || !matches!(v[1].0.tile_type, TileType::Friendly)
+        {
+            continue;
+        }
+        let snapshot = match available.get_mut(&from) {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+        // first kind that has somewhere to go
+        let kind = ResourceKind::ALL.into_iter().find(|&kind| {
+            snapshot.get(kind) > 0
+                && v[0].1.get(kind) > 0
+                && v[1].1.space_for(kind, rules.tile_capacity) > 0
+        });
+        if let Some(kind) = kind {
+            snapshot.take(kind, 1);
+            let moved = v[0].1.take(kind, 1);
+            v[1].1.add(kind, moved);
+        }
+    }
+}
@@ -1,18 +1,73 @@
-use bevy::prelude::*;
-use bevy_rapier2d::prelude::*;
+use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
 
 use crate::{
-    grid::{self, Grid, Tile, GRID_HEIGHT, GRID_WIDTH, TILE_SIZE},
-    health::Health,
+    boss,
+    flow_field::FlowFieldGoal,
+    grid::{Coords, Grid, GridLayout, Tile, STRUCTURE_COST},
+    health::{DamageNumbers, Health, HealthBar},
+    level::{LevelEntity, LevelSpawns},
+    resource_container::{Resource, ResourceKind},
+    spatial::EnemySpatialIndex,
+    visuals::{VisualsCache, HAZARD_COLOUR},
 };
 
 pub struct EnemyPlugin;
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(spawn_enemy)
+        app.insert_resource(SeparationSettings::default())
+            .add_event::<EnemyDiedEvent>()
+            .add_system(spawn_enemy)
+            .add_system(attack_structures.before(move_enemy))
             .add_system(move_enemy)
-            .add_system(enemy_death);
+            .add_system(separate_enemies.after(move_enemy))
+            .add_system(heal_allies)
+            .add_system(enemy_death)
+            .add_system(split_on_death)
+            .add_system(explode_on_death)
+            .add_system(leave_hazard_on_death)
+            .add_system(tick_hazards);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MovementLayer {
+    // follows the flow field
+    Ground,
+    // flies straight to the goal over everything
+    Air,
+}
+
+/// Which [`MovementLayer`]s something can hit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TargetLayers {
+    pub ground: bool,
+    pub air: bool,
+}
+
+impl TargetLayers {
+    pub const GROUND: TargetLayers = TargetLayers {
+        ground: true,
+        air: false,
+    };
+    pub const ALL: TargetLayers = TargetLayers {
+        ground: true,
+        air: true,
+    };
+
+    /// Just the one layer.
+    pub fn only(layer: MovementLayer) -> TargetLayers {
+        TargetLayers {
+            ground: layer == MovementLayer::Ground,
+            air: layer == MovementLayer::Air,
+        }
+    }
+
+    pub fn contains(&self, layer: MovementLayer) -> bool {
+        match layer {
+            MovementLayer::Ground => self.ground,
+            MovementLayer::Air => self.air,
+        }
     }
 }
 
@@ -21,6 +76,10 @@ pub struct Enemy {
     dir: Vec2,
     pos: Option<Vec2>,
     speed: f32,
+    radius: f32,
+    // heavier enemies push lighter ones out of the way
+    mass: f32,
+    layer: MovementLayer,
 }
 
 impl Enemy {
@@ -29,102 +88,819 @@ impl Enemy {
             dir: Vec2::new(1.0, 0.0),
             pos: None,
             speed: 25.0,
+            // sprite is 25x25
+            radius: 12.5,
+            mass: 1.0,
+            layer: MovementLayer::Ground,
         }
     }
 
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn layer(&self) -> MovementLayer {
+        self.layer
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    pub fn velocity(&self) -> Vec2 {
+        self.dir * self.speed
+    }
+
     pub fn die(&self, commands: &mut Commands, self_entity: Entity) {
         // should just have a system that checks for death instead
         commands.entity(self_entity).despawn_recursive();
     }
 }
 
-fn spawn_enemy(mut commands: Commands, keyboard: Res<Input<KeyCode>>) {
+#[derive(Component, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EnemyType {
+    Basic,
+    // heals other enemies around it
+    Healer,
+    // has a recharging shield on top of its health
+    Shielded,
+    // breaks through walls and towers in its way
+    Siege,
+    // ignores the flow field. Only anti-air can hit it
+    Flyer,
+    // bursts into swarmers when it dies
+    Splitter,
+    // small and fast
+    Swarmer,
+    // boss. Summons swarmers and takes over territory as it gets hurt
+    Warlord,
+}
+
+impl EnemyType {
+    fn colour(&self) -> Color {
+        match self {
+            EnemyType::Basic => Color::CRIMSON,
+            EnemyType::Healer => Color::LIME_GREEN,
+            EnemyType::Shielded => Color::TEAL,
+            EnemyType::Siege => Color::MAROON,
+            EnemyType::Flyer => Color::PINK,
+            EnemyType::Splitter => Color::ORANGE_RED,
+            EnemyType::Swarmer => Color::SALMON,
+            EnemyType::Warlord => Color::INDIGO,
+        }
+    }
+
+    /// Paid out when one dies.
+    /// Tougher enemies give stone and mana too, so towers that need them
+    /// can be bought before there's a quarry or well.
+    pub fn bounty(&self) -> Resource {
+        use ResourceKind::*;
+        match self {
+            EnemyType::Basic => Resource::of(Gold, 1),
+            EnemyType::Healer => Resource::of(Gold, 2).with(Mana, 1),
+            EnemyType::Shielded => Resource::of(Gold, 2).with(Mana, 1),
+            EnemyType::Siege => Resource::of(Gold, 3).with(Stone, 2),
+            EnemyType::Flyer => Resource::of(Gold, 2),
+            EnemyType::Splitter => Resource::of(Gold, 2).with(Stone, 1),
+            // the splitter already paid for these
+            EnemyType::Swarmer => Resource::default(),
+            EnemyType::Warlord => Resource::of(Gold, 25).with(Stone, 10).with(Mana, 5),
+        }
+    }
+
+    /// For wave definitions.
+    /// Bosses show on the boss bar instead of having their own health bar.
+    pub fn is_boss(&self) -> bool {
+        matches!(self, EnemyType::Warlord)
+    }
+
+    fn enemy(&self) -> Enemy {
+        match self {
+            EnemyType::Shielded => Enemy {
+                mass: 1.5,
+                ..Enemy::new()
+            },
+            EnemyType::Siege => Enemy {
+                speed: 20.0,
+                radius: 16.0,
+                mass: 4.0,
+                ..Enemy::new()
+            },
+            EnemyType::Flyer => Enemy {
+                speed: 35.0,
+                radius: 10.0,
+                mass: 0.5,
+                layer: MovementLayer::Air,
+                ..Enemy::new()
+            },
+            EnemyType::Splitter => Enemy {
+                radius: 14.0,
+                mass: 2.0,
+                ..Enemy::new()
+            },
+            EnemyType::Swarmer => Enemy {
+                speed: 40.0,
+                radius: 7.0,
+                mass: 0.5,
+                ..Enemy::new()
+            },
+            EnemyType::Warlord => Enemy {
+                speed: 15.0,
+                radius: 24.0,
+                // pushes through swarms
+                mass: 20.0,
+                ..Enemy::new()
+            },
+            _ => Enemy::new(),
+        }
+    }
+
+    fn health(&self) -> Health {
+        match self {
+            EnemyType::Basic => Health::new(5),
+            EnemyType::Healer => Health::new(4).with_regen(0.5),
+            EnemyType::Shielded => Health::new(5).with_shield(5, 2.0, 2.0),
+            EnemyType::Siege => Health::new(10),
+            EnemyType::Flyer => Health::new(3),
+            EnemyType::Splitter => Health::new(6),
+            EnemyType::Swarmer => Health::new(1),
+            EnemyType::Warlord => Health::new(60),
+        }
+    }
+
+    fn death_ability(&self) -> Option<DeathAbility> {
+        match self {
+            EnemyType::Healer => Some(DeathAbility::Hazard(HazardInfo {
+                radius: 40.0,
+                damage: 1,
+                time_between_hits: 1.0,
+                lifetime: 6.0,
+            })),
+            EnemyType::Shielded => Some(DeathAbility::DropGold(2)),
+            EnemyType::Siege => Some(DeathAbility::Explode {
+                radius: 48.0,
+                damage: 5,
+            }),
+            EnemyType::Splitter => Some(DeathAbility::Split {
+                into: EnemyType::Swarmer,
+                count: 3,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn spawn_enemy_of_type(commands: &mut Commands, enemy_type: EnemyType, pos: Vec3) -> Entity {
+    spawn_enemy_with(commands, enemy_type, enemy_type.enemy(), pos)
+}
+
+fn spawn_enemy_with(
+    commands: &mut Commands,
+    enemy_type: EnemyType,
+    enemy: Enemy,
+    pos: Vec3,
+) -> Entity {
+    // flyers draw over ground enemies
+    let pos = match enemy.layer {
+        MovementLayer::Ground => pos,
+        MovementLayer::Air => pos + Vec3::new(0.0, 0.0, 0.1),
+    };
+    let size = enemy.radius * 2.0;
+    let mut enemy_commands = commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: enemy_type.colour(),
+            custom_size: Some(Vec2::splat(size)),
+            ..default()
+        },
+        transform: Transform::from_translation(pos),
+        ..default()
+    });
+    enemy_commands
+        .insert(enemy)
+        .insert(enemy_type)
+        .insert(enemy_type.health())
+        .insert(DamageNumbers::default())
+        .insert(LevelEntity);
+    if !enemy_type.is_boss() {
+        enemy_commands.insert(HealthBar::new(size, 0.5 * size + 5.5));
+    }
+    if let Some(boss) = boss::boss_for(enemy_type) {
+        enemy_commands.insert(boss);
+    }
+
+    match enemy_type {
+        EnemyType::Healer => {
+            enemy_commands.insert(Healer::new(64.0, 1, 1.0));
+        }
+        EnemyType::Siege => {
+            enemy_commands.insert(Attacker::new(2, 1.0));
+        }
+        _ => {}
+    }
+    if let Some(ability) = enemy_type.death_ability() {
+        enemy_commands.insert(ability);
+    }
+
+    enemy_commands.id()
+}
+
+fn spawn_enemy(
+    mut commands: Commands,
+    keyboard: Res<Input<KeyCode>>,
+    layout: Res<GridLayout>,
+    spawns: Res<LevelSpawns>,
+) {
+    let tile_pos = |x, y| layout.pos(Coords { x, y }).extend(0.1);
     if keyboard.just_pressed(KeyCode::E) {
-        let offset = Vec3::new(
-            -0.5 * ((GRID_WIDTH - 1) as f32) * TILE_SIZE,
-            -0.5 * ((GRID_HEIGHT - 1) as f32) * TILE_SIZE,
-            0.0,
+        for i in 0..layout.width {
+            for j in 0..layout.height {
+                spawn_enemy_of_type(&mut commands, EnemyType::Basic, tile_pos(i, j));
+            }
+        }
+    }
+
+    // one on each of the level's spawn points.
+    // a column of special enemies down the left side if it doesn't have any
+    let column_type = if keyboard.just_pressed(KeyCode::H) {
+        Some(EnemyType::Healer)
+    } else if keyboard.just_pressed(KeyCode::J) {
+        Some(EnemyType::Shielded)
+    } else if keyboard.just_pressed(KeyCode::K) {
+        Some(EnemyType::Siege)
+    } else if keyboard.just_pressed(KeyCode::L) {
+        Some(EnemyType::Flyer)
+    } else if keyboard.just_pressed(KeyCode::U) {
+        Some(EnemyType::Splitter)
+    } else {
+        None
+    };
+    if let Some(enemy_type) = column_type {
+        if spawns.0.is_empty() {
+            for j in 0..layout.height {
+                spawn_enemy_of_type(&mut commands, enemy_type, tile_pos(0, j));
+            }
+        }
+        for coords in &spawns.0 {
+            spawn_enemy_of_type(&mut commands, enemy_type, tile_pos(coords.x, coords.y));
+        }
+    }
+
+    // one boss at the first spawn point, or halfway up the left side
+    if keyboard.just_pressed(KeyCode::B) {
+        let pos = match spawns.0.first() {
+            Some(coords) => tile_pos(coords.x, coords.y),
+            None => tile_pos(0, layout.height / 2),
+        };
+        spawn_enemy_of_type(&mut commands, EnemyType::Warlord, pos);
+    }
+}
+
+#[derive(Component)]
+pub struct Healer {
+    radius: f32,
+    amount: u32,
+    timer: Timer,
+}
+
+impl Healer {
+    pub fn new(radius: f32, amount: u32, time_between_heals: f32) -> Self {
+        Healer {
+            radius,
+            amount,
+            timer: Timer::from_seconds(time_between_heals, true),
+        }
+    }
+}
+
+fn heal_allies(
+    mut q_healers: Query<(Entity, &Transform, &mut Healer)>,
+    mut q_health: Query<&mut Health, With<Enemy>>,
+    index: Res<EnemySpatialIndex>,
+    time: Res<Time>,
+) {
+    for (healer_ent, trans, mut healer) in &mut q_healers {
+        if !healer.timer.tick(time.delta()).just_finished() {
+            continue;
+        }
+        let allies = index.enemies_in_radius(
+            trans.translation.truncate(),
+            healer.radius,
+            TargetLayers::ALL,
         );
-        for i in 0..grid::GRID_WIDTH {
-            for j in 0..grid::GRID_HEIGHT {
-                let pos = Vec3::new(i as f32 * 32.0, j as f32 * 32.0, 0.1);
-                commands
-                    .spawn_bundle(SpriteBundle {
-                        sprite: Sprite {
-                            color: Color::CRIMSON,
-                            custom_size: Some(Vec2::splat(25.0)),
-                            ..default()
-                        },
-                        transform: Transform::from_translation(offset + pos),
-                        ..default()
-                    })
-                    .insert(Enemy::new())
-                    .insert(Health::new(5))
-                    .insert(Collider::cuboid(12.0, 12.0))
-                    .insert(Sensor)
-                    .insert(RigidBody::Dynamic);
+        for (ally, _) in allies {
+            if ally == healer_ent {
+                // healers don't heal themselves
+                continue;
+            }
+            if let Ok(mut health) = q_health.get_mut(ally) {
+                // don't trip change detection on allies that are already full
+                if health.current() < health.max() {
+                    health.heal(healer.amount);
+                }
+            }
+        }
+    }
+}
+
+/// Stops to attack walls and towers that are in the way instead of walking through them.
+#[derive(Component)]
+pub struct Attacker {
+    damage: u32,
+    timer: Timer,
+    target: Option<Entity>,
+}
+
+impl Attacker {
+    pub fn new(damage: u32, time_between_attacks: f32) -> Self {
+        Attacker {
+            damage,
+            timer: Timer::from_seconds(time_between_attacks, true),
+            target: None,
+        }
+    }
+
+    fn is_attacking(&self) -> bool {
+        self.target.is_some()
+    }
+}
+
+fn attack_structures(
+    mut q_attackers: Query<(&Transform, &mut Enemy, &mut Attacker)>,
+    q_tiles: Query<(&Transform, &Tile), Without<Enemy>>,
+    mut q_structures: Query<&mut Health, (With<Tile>, Without<Enemy>)>,
+    grid: Res<Grid>,
+    time: Res<Time>,
+) {
+    for (trans, mut enemy, mut attacker) in &mut q_attackers {
+        // keep hitting the current target until it's gone
+        let mut target = attacker
+            .target
+            .filter(|&t| matches!(q_structures.get(t), Ok(health) if !health.is_dead()));
+
+        if target.is_none() {
+            // is the tile we're walking into blocked by a structure?
+            let next_tile = enemy.pos.and_then(|pos| grid.get_vec2(pos));
+            if let Some(tile_ent) = next_tile {
+                if let Ok((tile_trans, tile)) = q_tiles.get(tile_ent) {
+                    let close = tile_trans
+                        .translation
+                        .truncate()
+                        .distance_squared(trans.translation.truncate())
+                        < grid.layout().tile_size * grid.layout().tile_size;
+                    if close && tile.cost >= STRUCTURE_COST && q_structures.contains(tile_ent) {
+                        target = Some(tile_ent);
+                    }
+                }
+            }
+        }
+
+        if attacker.is_attacking() && target.is_none() {
+            // done attacking. Find a new tile to walk to
+            enemy.pos = None;
+        }
+        attacker.target = target;
+
+        if let Some(target) = target {
+            // stand still while attacking
+            enemy.dir = Vec2::ZERO;
+            if attacker.timer.tick(time.delta()).just_finished() {
+                if let Ok(mut health) = q_structures.get_mut(target) {
+                    health.take_damage(attacker.damage);
+                }
             }
         }
     }
 }
 
+// furthest an enemy moves before the flow field is sampled again, in tiles.
+// Stops fast enemies and long frames from skipping past turns
+const MAX_STEP: f32 = 0.25;
+
 fn move_enemy(
     grid: Res<Grid>,
     q_tiles: Query<(&Transform, &Tile), Without<Enemy>>,
-    mut q_enemies: Query<(&mut Transform, &mut Enemy)>,
+    mut q_enemies: Query<(&mut Transform, &mut Enemy, Option<&Attacker>)>,
+    goal: Res<FlowFieldGoal>,
     time: Res<Time>,
 ) {
-    for (mut trans, mut enemy) in q_enemies.iter_mut() {
-        let mut want_pos = false;
-        if let Some(pos) = enemy.pos {
-            // you have a pos
-            if trans.translation.truncate().distance_squared(pos) < 1.0 {
-                // close to pos
-                // find a new one
-
-                // this is laggy
-                // will only update when reaching a tile, even if the
-                // flow field was recalculated
-                want_pos = true;
-            } else {
-                // keep going where you're going
+    for (mut trans, mut enemy, attacker) in q_enemies.iter_mut() {
+        if matches!(attacker, Some(attacker) if attacker.is_attacking()) {
+            continue;
+        }
+
+        if enemy.layer == MovementLayer::Air {
+            // fly straight at the goal. Walls and tile costs don't matter
+            let current = trans.translation.truncate();
+            let target = goal.0.map(|coords| grid.layout().pos(coords));
+            enemy.pos = target;
+            let (dir, distance) = match target {
+                Some(target) => (
+                    (target - current).normalize_or_zero(),
+                    target.distance(current),
+                ),
+                None => (Vec2::ZERO, 0.0),
+            };
+            enemy.dir = dir;
+            // don't fly past it
+            let step = (enemy.speed * time.delta_seconds()).min(distance);
+            trans.translation += (dir * step).extend(0.0);
+            continue;
+        }
+
+        steer_along_flow(
+            &mut enemy,
+            &mut trans,
+            &grid,
+            &q_tiles,
+            time.delta_seconds(),
+        );
+    }
+}
+
+/// Moves a ground enemy along the flow field, sampling it again every [`MAX_STEP`] tiles.
+fn steer_along_flow(
+    enemy: &mut Enemy,
+    trans: &mut Transform,
+    grid: &Grid,
+    q_tiles: &Query<(&Transform, &Tile), Without<Enemy>>,
+    delta_seconds: f32,
+) {
+    let layout = grid.layout();
+    let mut remaining = enemy.speed * delta_seconds;
+    while remaining > 0.0 {
+        let pos = trans.translation.truncate();
+        let current = grid
+            .get_vec2(pos)
+            .and_then(|entity| q_tiles.get(entity).ok())
+            .map(|(_, tile)| *tile);
+        // the tile we're walking into. Attackers check it for structures
+        enemy.pos = current.and_then(|tile| tile.next_pos);
+
+        if let Some(tile) = current {
+            if tile.weight == 0 {
+                // at destination
+                // stop on the centre instead of overshooting it
+                let to_goal = layout.pos(tile.coords) - pos;
+                if to_goal.length() <= remaining {
+                    trans.translation += to_goal.extend(0.0);
+                    enemy.dir = Vec2::ZERO;
+                    return;
+                }
             }
-        } else {
-            // find one if possible
-            want_pos = true;
-        }
-
-        // let mut dir = Vec3::new(-25.0, 0.0, 0.0);
-        if want_pos {
-            if let Some(entity) = grid.get_vec2(trans.translation.truncate()) {
-                if let Ok((_transform, tile)) = q_tiles.get(entity) {
-                    if let Some(dir_tile) = tile.next_pos {
-                        // trans.translation += dir.extend(0.0) * time.delta_seconds();
-                        // dir = dir_tile.extend(0.0) * 25.0;
-                        enemy.pos = Some(dir_tile);
-                        enemy.dir = (dir_tile - trans.translation.truncate()).normalize_or_zero();
-                    } else {
-                        // at destination
-                        // stop moving
-                        if enemy.dir != Vec2::ZERO {
-                            enemy.dir = Vec2::ZERO;
-                        }
-                    }
+        }
+
+        // fall back to the tile we're on if the neighbours cancel each other out
+        let dir = sample_flow(grid, q_tiles, pos)
+            .or_else(|| current.and_then(|tile| tile_flow(layout, &tile, pos)));
+        let dir = match dir {
+            Some(dir) => dir,
+            None => {
+                // nowhere to go
+                enemy.dir = Vec2::ZERO;
+                return;
+            }
+        };
+
+        let step = remaining.min(MAX_STEP * layout.tile_size);
+        enemy.dir = dir;
+        trans.translation += (dir * step).extend(0.0);
+        remaining -= step;
+    }
+}
+
+/// Which way the flow field points on a single tile.
+fn tile_flow(layout: &GridLayout, tile: &Tile, pos: Vec2) -> Option<Vec2> {
+    let center = layout.pos(tile.coords);
+    match tile.next_pos {
+        Some(next) => Some((next - center).normalize_or_zero()),
+        // the goal pulls enemies into its centre
+        None if tile.weight == 0 => Some((center - pos).normalize_or_zero()),
+        // can't reach the goal from here
+        None => None,
+    }
+}
+
+/// Flow direction at `pos`, bilinearly blended between the four nearest tile centres.
+/// Tiles enemies can't walk onto are left out, so they don't pull enemies into corners.
+fn sample_flow(
+    grid: &Grid,
+    q_tiles: &Query<(&Transform, &Tile), Without<Enemy>>,
+    pos: Vec2,
+) -> Option<Vec2> {
+    // in tiles, with tile (0, 0) centred on 0.0
+    let layout = grid.layout();
+    let local = (pos - layout.pos(Coords { x: 0, y: 0 })) / layout.tile_size;
+    let base = local.floor();
+    let frac = local - base;
+
+    let mut sum = Vec2::ZERO;
+    let mut total_weight = 0.0;
+    for (i, j) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let x = base.x as i32 + i;
+        let y = base.y as i32 + j;
+        if x < 0 || y < 0 {
+            continue;
+        }
+        let tile = match grid
+            .get_xy(x as usize, y as usize)
+            .and_then(|entity| q_tiles.get(entity).ok())
+        {
+            Some((_, tile)) => tile,
+            None => continue,
+        };
+        // rock, water, walls and towers
+        if tile.cost >= STRUCTURE_COST || tile.weight == u32::MAX {
+            continue;
+        }
+        let dir = match tile_flow(layout, tile, pos) {
+            Some(dir) => dir,
+            None => continue,
+        };
+        let weight_x = if i == 0 { 1.0 - frac.x } else { frac.x };
+        let weight_y = if j == 0 { 1.0 - frac.y } else { frac.y };
+        sum += dir * weight_x * weight_y;
+        total_weight += weight_x * weight_y;
+    }
+
+    // nothing walkable around, so the caller falls back to the tile it's on
+    if total_weight <= 0.0 {
+        return None;
+    }
+    // renormalised over the tiles that were kept
+    let dir = sum / total_weight;
+    if dir.length_squared() < 0.01 {
+        None
+    } else {
+        Some(dir.normalize())
+    }
+}
+
+/// How far apart enemies on the same layer try to stay.
+pub struct SeparationSettings {
+    // gap kept between the edges of two enemies
+    pub padding: f32,
+    // how much of the overlap gets fixed per second
+    pub strength: f32,
+}
+
+impl Default for SeparationSettings {
+    fn default() -> Self {
+        SeparationSettings {
+            padding: 2.0,
+            strength: 8.0,
+        }
+    }
+}
+
+fn separate_enemies(
+    mut q_enemies: Query<(Entity, &mut Transform, &Enemy)>,
+    index: Res<EnemySpatialIndex>,
+    settings: Res<SeparationSettings>,
+    time: Res<Time>,
+) {
+    let mut pushes = Vec::new();
+    for (entity, trans, enemy) in &q_enemies {
+        let pos = trans.translation.truncate();
+        let neighbours = index.enemies_in_radius(
+            pos,
+            enemy.radius + settings.padding,
+            TargetLayers::only(enemy.layer),
+        );
+
+        let mut push = Vec2::ZERO;
+        for (other, _) in neighbours {
+            if other == entity {
+                continue;
+            }
+            let (other_pos, other_enemy) = match q_enemies.get(other) {
+                Ok((_, other_trans, other_enemy)) => {
+                    (other_trans.translation.truncate(), other_enemy)
                 }
+                Err(_) => continue,
+            };
+            let offset = pos - other_pos;
+            let distance = offset.length();
+            let min_distance = enemy.radius + other_enemy.radius + settings.padding;
+            if distance >= min_distance {
+                continue;
             }
+            let away = if distance > 0.001 {
+                offset / distance
+            } else {
+                // right on top of each other. Split them in opposite directions
+                let angle = entity.id().min(other.id()) as f32 * 2.4;
+                let dir = Vec2::new(angle.cos(), angle.sin());
+                if entity.id() > other.id() {
+                    dir
+                } else {
+                    -dir
+                }
+            };
+            // the lighter one moves more
+            let share = other_enemy.mass / (enemy.mass + other_enemy.mass);
+            push += away * (min_distance - distance) * share;
         }
+        if push != Vec2::ZERO {
+            pushes.push((entity, push));
+        }
+    }
 
-        trans.translation += enemy.dir.extend(0.0) * enemy.speed * time.delta_seconds();
+    let amount = (settings.strength * time.delta_seconds()).min(1.0);
+    for (entity, push) in pushes {
+        if let Ok((_, mut trans, _)) = q_enemies.get_mut(entity) {
+            trans.translation += (push * amount).extend(0.0);
+        }
     }
 }
 
+/// What an enemy does when it dies.
+#[derive(Component, Copy, Clone, Debug)]
+pub enum DeathAbility {
+    // spawns smaller enemies where it died
+    Split { into: EnemyType, count: u32 },
+    // leaves an area that keeps hurting structures
+    Hazard(HazardInfo),
+    // damages walls and towers around it
+    Explode { radius: f32, damage: u32 },
+    // onto the tile it died on
+    DropGold(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HazardInfo {
+    pub radius: f32,
+    pub damage: u32,
+    pub time_between_hits: f32,
+    pub lifetime: f32,
+}
+
+/// Sent for every enemy that dies.
+/// Each [`DeathAbility`] has its own listener.
+pub struct EnemyDiedEvent {
+    pub pos: Vec2,
+    pub enemy_type: EnemyType,
+    pub ability: Option<DeathAbility>,
+    // where it was going. Passed on to anything it spawns
+    pub dir: Vec2,
+    pub next_pos: Option<Vec2>,
+}
+
 fn enemy_death(
     mut commands: Commands,
-    q_enemies: Query<(Entity, &Enemy, &Health), Changed<Health>>,
+    q_enemies: Query<
+        (
+            Entity,
+            &Transform,
+            &Enemy,
+            &EnemyType,
+            &Health,
+            Option<&DeathAbility>,
+        ),
+        Changed<Health>,
+    >,
+    mut ev_died: EventWriter<EnemyDiedEvent>,
 ) {
-    for (entity, enemy, health) in q_enemies.iter() {
+    for (entity, trans, enemy, &enemy_type, health, ability) in q_enemies.iter() {
         if health.is_dead() {
+            ev_died.send(EnemyDiedEvent {
+                pos: trans.translation.truncate(),
+                enemy_type,
+                ability: ability.copied(),
+                dir: enemy.dir,
+                next_pos: enemy.pos,
+            });
             enemy.die(&mut commands, entity);
         }
     }
 }
+
+fn split_on_death(
+    mut commands: Commands,
+    mut ev_died: EventReader<EnemyDiedEvent>,
+    grid: Res<Grid>,
+    q_tiles: Query<&Tile>,
+    settings: Res<SeparationSettings>,
+) {
+    for ev in ev_died.iter() {
+        if let Some(DeathAbility::Split { into, count }) = ev.ability {
+            let radius = into.enemy().radius;
+            // stay within half a tile of the parent
+            let spread = split_spread(count, radius + settings.padding * 0.5)
+                .min(grid.layout().tile_size * 0.5);
+            for i in 0..count {
+                let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+                let pos = ev.pos + Vec2::new(angle.cos(), angle.sin()) * spread;
+                // any that would land in a wall, on water or off the grid start where the parent died
+                let tile = grid
+                    .layout()
+                    .tile_at(pos)
+                    .and_then(|coords| grid.get_coords(coords))
+                    .and_then(|entity| q_tiles.get(entity).ok());
+                let pos = match tile {
+                    Some(tile) if tile.cost < STRUCTURE_COST => pos,
+                    _ => ev.pos,
+                };
+                // carry on from where the parent was heading
+                let enemy = Enemy {
+                    dir: ev.dir,
+                    pos: ev.next_pos,
+                    ..into.enemy()
+                };
+                spawn_enemy_with(&mut commands, into, enemy, pos.extend(0.1));
+            }
+        }
+    }
+}
+
+/// How far from the centre `count` children sit so neighbours on the ring
+/// are `2 * half_gap` apart.
+fn split_spread(count: u32, half_gap: f32) -> f32 {
+    if count < 2 {
+        return 0.0;
+    }
+    half_gap / (std::f32::consts::PI / count as f32).sin()
+}
+
+fn explode_on_death(
+    mut ev_died: EventReader<EnemyDiedEvent>,
+    mut q_structures: Query<(&Transform, &mut Health), (With<Tile>, Without<Enemy>)>,
+) {
+    for ev in ev_died.iter() {
+        if let Some(DeathAbility::Explode { radius, damage }) = ev.ability {
+            damage_structures_in_radius(&mut q_structures, ev.pos, radius, damage);
+        }
+    }
+}
+
+fn damage_structures_in_radius(
+    q_structures: &mut Query<(&Transform, &mut Health), (With<Tile>, Without<Enemy>)>,
+    pos: Vec2,
+    radius: f32,
+    damage: u32,
+) {
+    for (trans, mut health) in q_structures.iter_mut() {
+        if trans.translation.truncate().distance_squared(pos) < radius * radius && !health.is_dead()
+        {
+            health.take_damage(damage);
+        }
+    }
+}
+
+#[derive(Component)]
+struct Hazard {
+    pos: Vec2,
+    radius: f32,
+    damage: u32,
+    hit_timer: Timer,
+    lifetime: Timer,
+}
+
+fn leave_hazard_on_death(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut cache: ResMut<VisualsCache>,
+    mut ev_died: EventReader<EnemyDiedEvent>,
+) {
+    for ev in ev_died.iter() {
+        if let Some(DeathAbility::Hazard(info)) = ev.ability {
+            commands
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: cache.circle(&mut meshes, info.radius),
+                    material: cache.material(&mut materials, HAZARD_COLOUR),
+                    // under the enemies, over the tiles
+                    transform: Transform::from_translation(ev.pos.extend(0.05)),
+                    ..default()
+                })
+                .insert(LevelEntity)
+                .insert(Hazard {
+                    pos: ev.pos,
+                    radius: info.radius,
+                    damage: info.damage,
+                    hit_timer: Timer::from_seconds(info.time_between_hits, true),
+                    lifetime: Timer::from_seconds(info.lifetime, false),
+                });
+        }
+    }
+}
+
+fn tick_hazards(
+    mut commands: Commands,
+    mut q_hazards: Query<(Entity, &mut Hazard)>,
+    mut q_structures: Query<(&Transform, &mut Health), (With<Tile>, Without<Enemy>)>,
+    time: Res<Time>,
+) {
+    for (entity, mut hazard) in &mut q_hazards {
+        if hazard.hit_timer.tick(time.delta()).just_finished() {
+            damage_structures_in_radius(
+                &mut q_structures,
+                hazard.pos,
+                hazard.radius,
+                hazard.damage,
+            );
+        }
+        if hazard.lifetime.tick(time.delta()).just_finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
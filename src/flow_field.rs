@@ -1,13 +1,39 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
 use bevy::prelude::*;
 
-use crate::grid::{self, Coords, Grid};
+use crate::grid::{self, Coords, Grid, GridLayout, IMPASSABLE_COST};
 // use rand::prelude::*;
 
 pub struct FlowFieldPlugin;
 
 impl Plugin for FlowFieldPlugin {
-    fn build(&self, _app: &mut App) {
+    fn build(&self, app: &mut App) {
         // app.add_startup_system(test_flow);
+        app.insert_resource(FlowFieldGoal(None))
+            .add_event::<RecalculateFlowFieldEvent>()
+            .add_system_to_stage(CoreStage::PostUpdate, recalculate_flow_field);
+    }
+}
+
+/// Where the flow field currently leads.
+pub struct FlowFieldGoal(pub Option<Coords>);
+
+/// Send when tile costs change so the flow field is rebuilt towards the current goal.
+pub struct RecalculateFlowFieldEvent;
+
+// runs after Update so it sees the tile costs changed this frame
+fn recalculate_flow_field(
+    ev_recalculate: EventReader<RecalculateFlowFieldEvent>,
+    goal: Res<FlowFieldGoal>,
+    grid: Res<Grid>,
+    q_tiles: Query<&mut grid::Tile>,
+) {
+    if !ev_recalculate.is_empty() {
+        ev_recalculate.clear();
+        if let Some(destination) = goal.0 {
+            generate_flow_field_grid(destination, grid, q_tiles);
+        }
     }
 }
 
@@ -24,70 +50,43 @@ pub fn generate_flow_field_grid(
         t.next_pos = None;
     }
 
-    let width = grid::GRID_WIDTH;
-    let height = grid::GRID_HEIGHT;
+    let layout = *grid.layout();
 
-    let destination_index = calculate_index(destination.x, destination.y, height);
+    let destination_index = layout.index(destination.x, destination.y);
     if let Some(&dest_ent) = grid.tiles.get(destination_index) {
         if let Ok(mut destination_node) = q_tiles.get_mut(dest_ent) {
             destination_node.weight = 0;
 
-            let mut open_set = Vec::new();
-            let mut closed_set: Vec<usize> = Vec::new();
-
-            open_set.push(destination_index);
-
-            while !open_set.is_empty() {
-                // get the lowest weight in the open set
-                let mut current_node_index = None;
-                let mut current_lowest_weight = u32::MAX;
-                for &i in open_set.iter() {
-                    if let Some(&entity) = grid.tiles.get(i) {
-                        if let Ok(tile) = q_tiles.get(entity) {
-                            if tile.weight < current_lowest_weight {
-                                current_lowest_weight = tile.weight;
-                                current_node_index = Some(i);
-                            }
-                        }
-                    }
+            // lowest weight first. A tile can be in here more than once if a cheaper way
+            // to it turns up, the stale entries are skipped once it's closed
+            let mut open_set = BinaryHeap::new();
+            let mut closed_set = vec![false; grid.tiles.len()];
+
+            open_set.push(Reverse((0, destination_index)));
+
+            while let Some(Reverse((current_weight, current_node_index))) = open_set.pop() {
+                if closed_set[current_node_index] {
+                    continue;
                 }
-                if let Some(current_node_index) = current_node_index {
-                    // remove from the open list
-                    for (i, val) in open_set.iter().enumerate() {
-                        if *val == current_node_index {
-                            open_set.remove(i);
-                            break;
-                        }
+                closed_set[current_node_index] = true;
+
+                let neighbours = get_neighbour_indicies(current_node_index, &layout, false);
+                for n_index in neighbours {
+                    if closed_set[n_index] {
+                        continue;
                     }
 
-                    closed_set.push(current_node_index);
-
-                    let neighbours =
-                        get_neighbour_indicies(current_node_index, width, height, false);
-                    let current_node;
-                    if let Some(&entity) = grid.tiles.get(current_node_index) {
-                        if let Ok(tile) = q_tiles.get(entity).cloned() {
-                            current_node = tile;
-
-                            for n_index in neighbours {
-                                if closed_set.contains(&n_index) {
-                                    continue;
-                                }
-
-                                if let Some(&entity) = grid.tiles.get(n_index) {
-                                    if let Ok(mut neighbour_node) = q_tiles.get_mut(entity) {
-                                        let tentative_weight =
-                                            current_node.weight + neighbour_node.cost as u32;
-
-                                        if tentative_weight < neighbour_node.weight {
-                                            neighbour_node.weight = tentative_weight;
-
-                                            if !open_set.contains(&n_index) {
-                                                open_set.push(n_index);
-                                            }
-                                        }
-                                    }
-                                }
+                    if let Some(&entity) = grid.tiles.get(n_index) {
+                        if let Ok(mut neighbour_node) = q_tiles.get_mut(entity) {
+                            // water and rock. Left at u32::MAX
+                            if neighbour_node.cost == IMPASSABLE_COST {
+                                continue;
+                            }
+                            let tentative_weight = current_weight + neighbour_node.cost as u32;
+
+                            if tentative_weight < neighbour_node.weight {
+                                neighbour_node.weight = tentative_weight;
+                                open_set.push(Reverse((tentative_weight, n_index)));
                             }
                         }
                     }
@@ -96,7 +95,7 @@ pub fn generate_flow_field_grid(
 
             // calculate direction
             for i in 0..grid.tiles.len() {
-                let neighbours = get_neighbour_indicies(i, width, height, true);
+                let neighbours = get_neighbour_indicies(i, &layout, true);
 
                 // to fix the diagonals
                 // could make it so you can only take a diagonal
@@ -115,11 +114,8 @@ pub fn generate_flow_field_grid(
                             // destination
                             tile.next_pos = None;
                         } else {
-                            let small_coords = Coords {
-                                x: smallest_index / height,
-                                y: smallest_index % height,
-                            };
-                            tile.next_pos = Some(small_coords.get_vec2());
+                            let (x, y) = layout.xy(smallest_index);
+                            tile.next_pos = Some(layout.pos(Coords { x, y }));
                         }
                     }
                 }
@@ -129,53 +125,44 @@ pub fn generate_flow_field_grid(
     println!("Updated flow field");
 }
 
-fn get_neighbour_indicies(
-    index: usize,
-    width: usize,
-    height: usize,
-    eight_connected: bool,
-) -> Vec<usize> {
+fn get_neighbour_indicies(index: usize, layout: &GridLayout, eight_connected: bool) -> Vec<usize> {
     let mut v = Vec::new();
 
-    let x = index / height;
-    let y = index % height;
+    let (width, height) = (layout.width, layout.height);
+    let (x, y) = layout.xy(index);
 
     if y < height - 1 {
         // up
-        v.push(calculate_index(x, y + 1, height));
+        v.push(layout.index(x, y + 1));
     }
     if eight_connected && y < height - 1 && x < width - 1 {
         // up right
-        v.push(calculate_index(x + 1, y + 1, height));
+        v.push(layout.index(x + 1, y + 1));
     }
     if x < width - 1 {
         // right
-        v.push(calculate_index(x + 1, y, height));
+        v.push(layout.index(x + 1, y));
     }
     if eight_connected && y > 0 && x < width - 1 {
         // down right
-        v.push(calculate_index(x + 1, y - 1, height));
+        v.push(layout.index(x + 1, y - 1));
     }
     if y > 0 {
         // down
-        v.push(calculate_index(x, y - 1, height));
+        v.push(layout.index(x, y - 1));
     }
     if eight_connected && y > 0 && x > 0 {
         // down left
-        v.push(calculate_index(x - 1, y - 1, height));
+        v.push(layout.index(x - 1, y - 1));
     }
     if x > 0 {
         // left
-        v.push(calculate_index(x - 1, y, height));
+        v.push(layout.index(x - 1, y));
     }
     if eight_connected && x > 0 && y < height - 1 {
         // up left
-        v.push(calculate_index(x - 1, y + 1, height));
+        v.push(layout.index(x - 1, y + 1));
     }
 
     v
 }
-
-fn calculate_index(x: usize, y: usize, height: usize) -> usize {
-    y + x * height
-}
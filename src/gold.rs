@@ -3,34 +3,55 @@ use std::f32::consts;
 use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
 
 use crate::{
-    grid::{Coords, Grid, Tile, TileType},
-    resource_container::Resource,
+    enemy::{DeathAbility, EnemyDiedEvent},
+    grid::{Grid, Tile, TileType},
+    level::LevelEntity,
+    loading::FontAssets,
+    resource_container::{Resource, ResourceKind, StorageRules},
+    visuals::{VisualsCache, COIN_POP_RADIUS},
+    BountyPayout, GameMode, GameState,
 };
 
 pub struct GoldPlugin;
 
 impl Plugin for GoldPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<SpawnGoldEvent>()
+        app.add_system_set(SystemSet::on_exit(GameState::Loading).with_system(create_treasury_ui));
+        app.insert_resource(Treasury::new())
+            .add_event::<SpawnGoldEvent>()
             .add_system(tick_spawner)
             .add_system(tick_gold)
-            .add_system(spawn_gold);
+            .add_system(spawn_gold)
+            .add_system(drop_gold_on_death)
+            .add_system(pay_bounties)
+            .add_system(bank_deposits)
+            .add_system(update_treasury_ui);
     }
 }
 
+/// Produces a resource on the friendly tiles around it.
 #[derive(Component)]
-pub struct GoldSpawner {
+pub struct Generator {
+    kind: ResourceKind,
     timer: Timer,
     range: u32,
+    // whether it's put anything down yet
+    generated: bool,
 }
 
-impl GoldSpawner {
-    pub fn new() -> Self {
-        GoldSpawner {
+impl Generator {
+    pub fn new(kind: ResourceKind) -> Self {
+        Generator {
+            kind,
             timer: Timer::from_seconds(3.0, true),
             range: 2,
+            generated: false,
         }
     }
+
+    pub fn has_generated(&self) -> bool {
+        self.generated
+    }
 }
 
 #[derive(Component)]
@@ -48,24 +69,27 @@ impl Gold {
 
 struct SpawnGoldEvent {
     pos: Vec3,
+    kind: ResourceKind,
 }
 
 fn tick_spawner(
     mut q_tiles: Query<(&Transform, &Tile, &mut Resource)>,
-    mut q_gold_spawners: Query<(&Transform, &mut GoldSpawner)>,
+    mut q_generators: Query<(&Transform, &mut Generator)>,
     mut ev_spawn: EventWriter<SpawnGoldEvent>,
     time: Res<Time>,
     grid: Res<Grid>,
+    rules: Res<StorageRules>,
 ) {
     // is &mut query a better pattern than
     // query.iter_mut() ?
 
-    // for each spawner, spawn gold in the range around it
-    // only spawn gold on friendly territory
-    for (trans, mut spawner) in &mut q_gold_spawners {
+    // for each spawner, spawn its resource in the range around it
+    // only spawn on friendly territory
+    for (trans, mut spawner) in &mut q_generators {
         if spawner.timer.tick(time.delta()).just_finished() {
+            spawner.generated = true;
             // println!("Spawn a gold");
-            let center_coords = Coords::from_vec2(trans.translation.truncate());
+            let center_coords = grid.layout().coords(trans.translation.truncate());
             for i in 0..=spawner.range {
                 // get coords in range
                 let neighbours = grid.get_diamond_ring(center_coords, i as usize);
@@ -75,20 +99,22 @@ fn tick_spawner(
                     if let Ok((tile_trans, tile, mut res)) = q_tiles.get_mut(*n) {
                         // does tile.tile_type match my allegience?
                         if matches!(tile.tile_type, TileType::Friendly) {
-                            // spawn a gold there
+                            // the ground decides how much gold grows there
+                            let amount = match spawner.kind {
+                                ResourceKind::Gold => tile.terrain.gold_multiplier(),
+                                _ => 1,
+                            };
+                            // no coin pop for what the tile can't hold
+                            let amount =
+                                amount.min(res.space_for(spawner.kind, rules.tile_capacity));
+                            if amount == 0 {
+                                continue;
+                            }
                             ev_spawn.send(SpawnGoldEvent {
                                 pos: tile_trans.translation,
+                                kind: spawner.kind,
                             });
-
-                            match *res {
-                                Resource::None => {
-                                    *res = Resource::Gold(1);
-                                }
-                                Resource::Gold(mut count) => {
-                                    count += 1;
-                                    *res = Resource::Gold(count);
-                                }
-                            }
+                            res.add(spawner.kind, amount);
                         }
                     }
                 }
@@ -97,21 +123,183 @@ fn tick_spawner(
     }
 }
 
+/// Anything that ends up on a bank's tile goes into the treasury.
+#[derive(Component)]
+pub struct Bank;
+
+fn bank_deposits(
+    mut q_banks: Query<&mut Resource, (With<Bank>, Changed<Resource>)>,
+    mut treasury: ResMut<Treasury>,
+) {
+    for mut res in &mut q_banks {
+        if res.is_empty() {
+            continue;
+        }
+        for kind in ResourceKind::ALL {
+            let amount = res.take(kind, u32::MAX);
+            treasury.stock.add(kind, amount);
+        }
+    }
+}
+
+/// Resources the player can spend.
+pub struct Treasury {
+    pub stock: Resource,
+}
+
+impl Treasury {
+    fn new() -> Self {
+        // enough for a couple of towers
+        Treasury {
+            stock: Resource::of(ResourceKind::Gold, 20)
+                .with(ResourceKind::Stone, 10)
+                .with(ResourceKind::Mana, 5),
+        }
+    }
+}
+
+fn drop_gold_on_death(
+    mut ev_died: EventReader<EnemyDiedEvent>,
+    mut q_tiles: Query<(&Transform, &mut Resource), With<Tile>>,
+    mut ev_spawn: EventWriter<SpawnGoldEvent>,
+    grid: Res<Grid>,
+    rules: Res<StorageRules>,
+) {
+    for ev in ev_died.iter() {
+        if let Some(DeathAbility::DropGold(amount)) = ev.ability {
+            let gold = Resource::of(ResourceKind::Gold, amount);
+            drop_resources(&mut q_tiles, &mut ev_spawn, &grid, &rules, ev.pos, gold);
+        }
+    }
+}
+
+fn pay_bounties(
+    mut ev_died: EventReader<EnemyDiedEvent>,
+    mut q_tiles: Query<(&Transform, &mut Resource), With<Tile>>,
+    mut ev_spawn: EventWriter<SpawnGoldEvent>,
+    mut treasury: ResMut<Treasury>,
+    grid: Res<Grid>,
+    rules: Res<StorageRules>,
+    mode: Res<GameMode>,
+) {
+    for ev in ev_died.iter() {
+        let bounty = ev.enemy_type.bounty();
+        if bounty.is_empty() {
+            continue;
+        }
+        match mode.bounty_payout() {
+            BountyPayout::Treasury => {
+                for (kind, amount) in bounty.iter() {
+                    treasury.stock.add(kind, amount);
+                }
+            }
+            BountyPayout::Drop => {
+                drop_resources(&mut q_tiles, &mut ev_spawn, &grid, &rules, ev.pos, bounty);
+            }
+        }
+    }
+}
+
+/// Adds `dropped` to the stacks on the tile at `pos`, with a popping coin for each kind.
+/// Only as much as the tile has room for.
+fn drop_resources(
+    q_tiles: &mut Query<(&Transform, &mut Resource), With<Tile>>,
+    ev_spawn: &mut EventWriter<SpawnGoldEvent>,
+    grid: &Grid,
+    rules: &StorageRules,
+    pos: Vec2,
+    dropped: Resource,
+) {
+    let tile = grid.get_vec2(pos);
+    if let Some((tile_trans, mut res)) = tile.and_then(|t| q_tiles.get_mut(t).ok()) {
+        for (kind, amount) in dropped.iter() {
+            let amount = amount.min(res.space_for(kind, rules.tile_capacity));
+            if amount == 0 {
+                continue;
+            }
+            ev_spawn.send(SpawnGoldEvent {
+                pos: tile_trans.translation,
+                kind,
+            });
+            res.add(kind, amount);
+        }
+    }
+}
+
+#[derive(Component)]
+struct TreasuryText;
+
+fn create_treasury_ui(mut commands: Commands, fonts: Res<FontAssets>) {
+    commands
+        .spawn_bundle(
+            // a section for each kind, in its own colour, then the game mode
+            TextBundle::from_sections(
+                ResourceKind::ALL
+                    .iter()
+                    .map(|kind| kind.colour())
+                    .chain([Color::rgb(0.9, 0.9, 0.9)])
+                    .map(|color| {
+                        TextSection::new(
+                            "",
+                            TextStyle {
+                                font: fonts.fira_sans.clone(),
+                                font_size: 24.0,
+                                color,
+                            },
+                        )
+                    }),
+            )
+            .with_style(Style {
+                // top left of the screen
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(8.0),
+                    top: Val::Px(8.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(TreasuryText);
+}
+
+fn update_treasury_ui(
+    treasury: Res<Treasury>,
+    mode: Res<GameMode>,
+    mut q_text: Query<&mut Text, With<TreasuryText>>,
+    q_new_text: Query<(), Added<TreasuryText>>,
+) {
+    // the text starts empty so fill it in the frame it's made too
+    if !treasury.is_changed() && !mode.is_changed() && q_new_text.is_empty() {
+        return;
+    }
+    for mut text in &mut q_text {
+        for (section, kind) in text.sections.iter_mut().zip(ResourceKind::ALL) {
+            section.value = format!("{}: {}  ", kind.name(), treasury.stock.get(kind));
+        }
+        if let Some(section) = text.sections.get_mut(ResourceKind::ALL.len()) {
+            section.value = format!("{} (M)", mode.name());
+        }
+    }
+}
+
 fn spawn_gold(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    mut cache: ResMut<VisualsCache>,
     mut ev_gold: EventReader<SpawnGoldEvent>,
 ) {
     for ev in ev_gold.iter() {
         commands
             .spawn_bundle(MaterialMesh2dBundle {
-                mesh: meshes.add(shape::Circle::new(12.0).into()).into(),
-                material: materials.add(ColorMaterial::from(Color::GOLD)),
+                mesh: cache.circle(&mut meshes, COIN_POP_RADIUS),
+                material: cache.material(&mut materials, ev.kind.colour()),
                 transform: Transform::from_translation(ev.pos + Vec3::new(0.0, 0.0, 0.1)),
                 ..default()
             })
-            .insert(Gold::new());
+            .insert(Gold::new())
+            .insert(LevelEntity);
     }
 }
 
@@ -3,7 +3,11 @@ use std::{f32::consts::FRAC_PI_2, time::Duration};
 use bevy::prelude::*;
 
 use crate::{
-    flow_field::generate_flow_field_grid, resource_container::Resource, swap::Draggable,
+    flow_field::{generate_flow_field_grid, FlowFieldGoal, RecalculateFlowFieldEvent},
+    health::{Health, HealthBar},
+    level::LevelEntity,
+    resource_container::{Resource, ResourceStack},
+    swap::{Draggable, SwapTween},
     MouseWorldPos,
 };
 
@@ -11,29 +15,34 @@ pub struct GridPlugin;
 
 impl Plugin for GridPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Grid {
-            tiles: Vec::with_capacity(GRID_WIDTH * GRID_HEIGHT),
-        })
-        .insert_resource(TileColours::new())
-        .add_event::<ClearSelectionsEvent>()
-        .add_event::<ChangeAllegianceEvent>()
-        .add_startup_system(setup_grid)
-        .add_system(clear_interaction.before(check_interaction))
-        .add_system(check_interaction.before(tile_interaction))
-        .add_system(tile_interaction.before(clear_selection))
-        .add_system(clear_selection)
-        .add_system(gen_flow_field)
-        // .add_system(change_alegience.after(tile_interaction))
-        .add_system(change_allegiance.after(tile_interaction))
-        // .add_system(change_colour_animation)
-        .add_system(territory_flip_animation)
-        .add_system(grab_territory);
-    }
-}
-
-pub const GRID_WIDTH: usize = 20;
-pub const GRID_HEIGHT: usize = 20;
-pub const TILE_SIZE: f32 = 32.0;
+        let layout = GridLayout::default();
+        app.insert_resource(layout)
+            .insert_resource(Grid::new(layout))
+            .insert_resource(TileColours::new())
+            .add_event::<ClearSelectionsEvent>()
+            .add_event::<ChangeAllegianceEvent>()
+            .add_event::<StructureDestroyedEvent>()
+            .add_event::<RemoveStructureEvent>()
+            .add_startup_system(setup_grid)
+            .add_system(clear_interaction.before(check_interaction))
+            .add_system(check_interaction.before(tile_interaction))
+            .add_system(tile_interaction.before(clear_selection))
+            .add_system(clear_selection)
+            .add_system(gen_flow_field)
+            // .add_system(change_alegience.after(tile_interaction))
+            .add_system(change_allegiance.after(tile_interaction))
+            // .add_system(change_colour_animation)
+            .add_system(territory_flip_animation)
+            .add_system(grab_territory)
+            .add_system(destroy_structures)
+            .add_system(remove_structures);
+    }
+}
+
+// cost of a tile with a wall or tower on it
+pub const STRUCTURE_COST: u8 = 200;
+// ground enemies never path through tiles with this cost
+pub const IMPASSABLE_COST: u8 = u8::MAX;
 
 // events
 pub struct ClearSelectionsEvent;
@@ -44,32 +53,92 @@ pub struct Coords {
     pub y: usize,
 }
 
-impl Coords {
-    pub fn from_vec2(pos: Vec2) -> Self {
-        let x = (((GRID_WIDTH - 1) as f32 * 0.5 * TILE_SIZE) + TILE_SIZE * 0.5 + pos.x) / TILE_SIZE;
-        let y =
-            (((GRID_HEIGHT - 1) as f32 * 0.5 * TILE_SIZE) + TILE_SIZE * 0.5 + pos.y) / TILE_SIZE;
+/// How big the map is and how big each tile is. Made at runtime so levels can be any size.
+/// The grid is centred on the origin.
+///
+/// Everything that turns world positions into [`Coords`] and back goes through here.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GridLayout {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: f32,
+}
 
-        // if x < 0.0 || y < 0.0 {
-        //     println!("x or y < 0 x,y:{:?}, {:?}", x, y);
-        //     println!("converted to usize: {:?}, {:?}", x as usize, y as usize);
-        // }
-        // should I clamp?
-        // lower bounds are auto clamped by converting to usize
+impl Default for GridLayout {
+    fn default() -> Self {
+        GridLayout {
+            width: 20,
+            height: 20,
+            tile_size: 32.0,
+        }
+    }
+}
 
+impl GridLayout {
+    pub fn tile_count(&self) -> usize {
+        self.width * self.height
+    }
+
+    // centre of the bottom left tile
+    fn origin(&self) -> Vec2 {
+        Vec2::new(
+            -0.5 * ((self.width - 1) as f32) * self.tile_size,
+            -0.5 * ((self.height - 1) as f32) * self.tile_size,
+        )
+    }
+
+    // tile units from the bottom left corner of the grid. Negative off the left or bottom
+    fn tile_units(&self, pos: Vec2) -> Vec2 {
+        (pos - self.origin()) / self.tile_size + Vec2::splat(0.5)
+    }
+
+    /// Coords of the tile at `pos`. Positions off the left or bottom are clamped to 0.
+    /// Positions off the right or top aren't, so they can be checked with [`GridLayout::contains`].
+    pub fn coords(&self, pos: Vec2) -> Coords {
+        let units = self.tile_units(pos);
         // negative values get clamped to 0
         Coords {
-            x: x as usize,
-            y: y as usize,
+            x: units.x as usize,
+            y: units.y as usize,
         }
     }
 
-    pub fn get_vec2(&self) -> Vec2 {
-        let offset = Vec2::new(
-            -0.5 * ((GRID_WIDTH - 1) as f32) * TILE_SIZE,
-            -0.5 * ((GRID_HEIGHT - 1) as f32) * TILE_SIZE,
-        );
-        offset + Vec2::new(self.x as f32 * TILE_SIZE, self.y as f32 * TILE_SIZE)
+    /// Coords of the tile nearest to `pos`, even when it's off the grid.
+    pub fn clamped_coords(&self, pos: Vec2) -> Coords {
+        let coords = self.coords(pos);
+        Coords {
+            x: coords.x.min(self.width - 1),
+            y: coords.y.min(self.height - 1),
+        }
+    }
+
+    /// Coords of the tile at `pos`, or None when it's off the grid.
+    pub fn tile_at(&self, pos: Vec2) -> Option<Coords> {
+        let units = self.tile_units(pos);
+        if units.x < 0.0 || units.y < 0.0 {
+            return None;
+        }
+        let coords = self.coords(pos);
+        self.contains(coords.x, coords.y).then_some(coords)
+    }
+
+    /// World position of the centre of the tile at `coords`.
+    pub fn pos(&self, coords: Coords) -> Vec2 {
+        self.origin() + Vec2::new(coords.x as f32, coords.y as f32) * self.tile_size
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    /// Where the tile at `x`, `y` is kept in [`Grid`] and other per tile lists.
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        x * self.height + y
+    }
+
+    /// The `x`, `y` of an index from [`GridLayout::index`].
+    pub fn xy(&self, index: usize) -> (usize, usize) {
+        (index / self.height, index % self.height)
     }
 }
 
@@ -77,64 +146,131 @@ impl Coords {
 pub struct Tile {
     pub coords: Coords,
     pub cost: u8,
+    // cost to go back to when a structure is removed
+    pub base_cost: u8,
     pub weight: u32,
     pub next_pos: Option<Vec2>,
     pub tile_type: TileType,
+    pub terrain: Terrain,
 }
 
 impl Tile {
-    fn new(x: usize, y: usize) -> Self {
+    pub fn new(x: usize, y: usize) -> Self {
         Tile {
             coords: Coords { x, y },
-            cost: 1,
+            cost: Terrain::Grass.move_cost(),
+            base_cost: Terrain::Grass.move_cost(),
             weight: u32::MAX,
             next_pos: None,
             tile_type: TileType::Neutral,
+            terrain: Terrain::Grass,
         }
     }
 
+    /// Changes the ground and the cost of walking over it.
+    pub fn with_terrain(mut self, terrain: Terrain) -> Self {
+        self.terrain = terrain;
+        self.base_cost = terrain.move_cost();
+        self.cost = terrain.move_cost();
+        self
+    }
+
+    /// Whether a wall or tower can go here.
+    pub fn can_build(&self) -> bool {
+        self.cost < STRUCTURE_COST && self.terrain.is_buildable()
+    }
+
+    #[allow(clippy::manual_is_multiple_of)]
     pub fn is_even(&self) -> bool {
         (self.coords.x + self.coords.y) % 2 == 0
     }
 
-    /// Updates the tile's colour to what it should be based on its [`TileType`] and `self.is_even()`.
+    /// Updates the tile's colour to what it should be based on its [`Terrain`], [`TileType`] and `self.is_even()`.
     ///  
     /// `sprite` is the [`Sprite`] to update.
     /// `tile_colours` is the Res that holds the possible colours.
-    fn update_colour(&self, sprite: &mut Sprite, tile_colours: &Res<TileColours>) {
-        match self.tile_type {
-            TileType::Neutral => {
-                if self.is_even() {
-                    if sprite.color != tile_colours.even_grass {
-                        sprite.color = tile_colours.even_grass;
-                    }
-                } else if sprite.color != tile_colours.odd_grass {
-                    sprite.color = tile_colours.odd_grass;
-                }
-            }
-            TileType::Friendly => {
-                if self.is_even() {
-                    if sprite.color != tile_colours.even_friend {
-                        sprite.color = tile_colours.even_friend;
-                    }
-                } else if sprite.color != tile_colours.odd_friend {
-                    sprite.color = tile_colours.odd_friend;
-                }
-            }
-            TileType::Hostile => {
-                if self.is_even() {
-                    if sprite.color != tile_colours.even_hostile {
-                        sprite.color = tile_colours.even_hostile;
-                    }
-                } else if sprite.color != tile_colours.odd_hostile {
-                    sprite.color = tile_colours.odd_hostile;
-                }
-            }
+    fn update_colour(&self, sprite: &mut Sprite, tile_colours: &TileColours) {
+        let even = self.is_even();
+        let territory = match self.tile_type {
+            TileType::Neutral if even => tile_colours.even_grass,
+            TileType::Neutral => tile_colours.odd_grass,
+            TileType::Friendly if even => tile_colours.even_friend,
+            TileType::Friendly => tile_colours.odd_friend,
+            TileType::Hostile if even => tile_colours.even_hostile,
+            TileType::Hostile => tile_colours.odd_hostile,
+        };
+        let colour = match self.terrain {
+            Terrain::Grass => territory,
+            // water and rock look the same whoever owns them
+            Terrain::Water => tile_colours.water,
+            Terrain::Rock => tile_colours.rock,
+            // the rest show the territory through them
+            Terrain::Road => mix(tile_colours.road, territory, 0.3),
+            Terrain::Mud => mix(tile_colours.mud, territory, 0.3),
+            Terrain::Forest => mix(tile_colours.forest, territory, 0.3),
+        };
+        if sprite.color != colour {
+            sprite.color = colour;
         }
     }
 }
 
-#[derive(Copy, Clone)]
+/// `t` of the way from `a` to `b`.
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    let [ar, ag, ab, aa] = a.as_rgba_f32();
+    let [br, bg, bb, ba] = b.as_rgba_f32();
+    Color::rgba(
+        ar + (br - ar) * t,
+        ag + (bg - ag) * t,
+        ab + (bb - ab) * t,
+        aa + (ba - aa) * t,
+    )
+}
+
+/// What the ground is made of. Moves with the tile when it's swapped.
+/// Water and rock can't be walked over or built on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Terrain {
+    Grass,
+    Road,
+    Mud,
+    Water,
+    Rock,
+    Forest,
+}
+
+impl Terrain {
+    pub fn is_passable(&self) -> bool {
+        self.move_cost() != IMPASSABLE_COST
+    }
+
+    /// What it costs enemies to walk over. Roads are the quickest way through.
+    pub fn move_cost(&self) -> u8 {
+        match self {
+            Terrain::Road => 1,
+            Terrain::Grass => 2,
+            Terrain::Forest => 3,
+            Terrain::Mud => 5,
+            Terrain::Water | Terrain::Rock => IMPASSABLE_COST,
+        }
+    }
+
+    /// Whether walls and towers can go on it. Roads are kept clear.
+    pub fn is_buildable(&self) -> bool {
+        matches!(self, Terrain::Grass | Terrain::Forest)
+    }
+
+    /// How many gold a generator makes here each tick.
+    pub fn gold_multiplier(&self) -> u32 {
+        match self {
+            Terrain::Forest => 2,
+            Terrain::Grass | Terrain::Road => 1,
+            Terrain::Mud | Terrain::Water | Terrain::Rock => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TileType {
     Neutral,
     Friendly,
@@ -143,27 +279,34 @@ pub enum TileType {
 
 pub struct Grid {
     pub tiles: Vec<Entity>,
+    // the layout the tiles were made with
+    layout: GridLayout,
 }
 
 impl Grid {
-    pub fn get_vec2(&self, pos: Vec2) -> Option<Entity> {
-        let x = (((GRID_WIDTH - 1) as f32 * 0.5 * TILE_SIZE) + TILE_SIZE * 0.5 + pos.x) / TILE_SIZE;
-        let y =
-            (((GRID_HEIGHT - 1) as f32 * 0.5 * TILE_SIZE) + TILE_SIZE * 0.5 + pos.y) / TILE_SIZE;
-
-        if x < 0.0 || y < 0.0 {
-            return None;
+    pub fn new(layout: GridLayout) -> Self {
+        Grid {
+            tiles: Vec::with_capacity(layout.tile_count()),
+            layout,
         }
+    }
 
-        self.get_xy(x as usize, y as usize)
+    pub fn layout(&self) -> &GridLayout {
+        &self.layout
+    }
+
+    pub fn get_vec2(&self, pos: Vec2) -> Option<Entity> {
+        self.layout
+            .tile_at(pos)
+            .and_then(|coords| self.get_coords(coords))
     }
 
     pub fn get_xy(&self, x: usize, y: usize) -> Option<Entity> {
-        if x >= GRID_WIDTH || y >= GRID_HEIGHT {
+        if !self.layout.contains(x, y) {
             return None;
         }
 
-        let index = x * GRID_HEIGHT + y;
+        let index = self.layout.index(x, y);
         let ent = self.tiles.get(index);
         ent.copied()
     }
@@ -173,9 +316,47 @@ impl Grid {
         self.get_xy(coords.x, coords.y)
     }
 
+    /// Every tile in the rectangle with corners `a` and `b`, inclusive.
+    pub fn get_rect(&self, a: Coords, b: Coords) -> Vec<Entity> {
+        let mut v = Vec::new();
+        for x in a.x.min(b.x)..=a.x.max(b.x) {
+            for y in a.y.min(b.y)..=a.y.max(b.y) {
+                v.extend(self.get_xy(x, y));
+            }
+        }
+        v
+    }
+
+    /// Tiles on the straight line from `a` to `b`, in order.
+    /// Each step shares an edge with the last so walls along it leave no gaps.
+    pub fn get_line(&self, a: Coords, b: Coords) -> Vec<Entity> {
+        let (mut x, mut y) = (a.x as i32, a.y as i32);
+        let dx = (b.x as i32 - x).abs();
+        let dy = (b.y as i32 - y).abs();
+        let step_x = (b.x as i32 - x).signum();
+        let step_y = (b.y as i32 - y).signum();
+
+        let mut v = Vec::new();
+        v.extend(self.get_xy(x as usize, y as usize));
+        // steps taken along each axis
+        let (mut ix, mut iy) = (0, 0);
+        while ix < dx || iy < dy {
+            // step along whichever axis reaches its next tile edge first
+            if (1 + 2 * ix) * dy < (1 + 2 * iy) * dx {
+                x += step_x;
+                ix += 1;
+            } else {
+                y += step_y;
+                iy += 1;
+            }
+            v.extend(self.get_xy(x as usize, y as usize));
+        }
+        v
+    }
+
     pub fn swap(&mut self, a: Coords, b: Coords) {
-        let a_index = a.x * GRID_HEIGHT + a.y;
-        let b_index = b.x * GRID_HEIGHT + b.y;
+        let a_index = self.layout.index(a.x, a.y);
+        let b_index = self.layout.index(b.x, b.y);
 
         self.tiles.swap(a_index, b_index);
     }
@@ -225,6 +406,7 @@ impl Grid {
     /// `distance`: 1 is the 4 cardinal neighbours around the tile.</br>
     /// `distance`: 2 is the 8 tiles in a diamond adjacent to those 4.
     // #[allow(unused_comparisons)] // reason = "Compare >= 0 before converting to usize")
+    #[allow(clippy::explicit_counter_loop)]
     pub fn get_diamond_ring(&self, center: Coords, distance: usize) -> Vec<Option<Entity>> {
         let mut v = Vec::new();
 
@@ -290,13 +472,18 @@ impl Grid {
     }
 }
 
-struct TileColours {
+pub struct TileColours {
     even_grass: Color,
     odd_grass: Color,
     even_friend: Color,
     odd_friend: Color,
     even_hostile: Color,
     odd_hostile: Color,
+    water: Color,
+    rock: Color,
+    road: Color,
+    mud: Color,
+    forest: Color,
     hover_color: Color,
     select_color: Color,
 }
@@ -314,6 +501,16 @@ impl TileColours {
             // #3e2731 #a22633
             odd_hostile: Color::rgb_u8(0x3e, 0x27, 0x31),
             even_hostile: Color::rgb_u8(0xa2, 0x26, 0x33),
+            // #2ce8f5
+            water: Color::rgb_u8(0x2c, 0xe8, 0xf5),
+            // #5a6988
+            rock: Color::rgb_u8(0x5a, 0x69, 0x88),
+            // #c0cbdc
+            road: Color::rgb_u8(0xc0, 0xcb, 0xdc),
+            // #733e39
+            mud: Color::rgb_u8(0x73, 0x3e, 0x39),
+            // #193c3e
+            forest: Color::rgb_u8(0x19, 0x3c, 0x3e),
             hover_color: Color::ALICE_BLUE,
             select_color: Color::MIDNIGHT_BLUE,
         }
@@ -323,46 +520,51 @@ impl TileColours {
 #[derive(Component)]
 pub struct Selection;
 
-fn setup_grid(mut commands: Commands, mut grid: ResMut<Grid>, tile_colours: Res<TileColours>) {
-    let offset = Vec3::new(
-        -0.5 * ((GRID_WIDTH - 1) as f32) * TILE_SIZE,
-        -0.5 * ((GRID_HEIGHT - 1) as f32) * TILE_SIZE,
-        0.0,
-    );
-
-    for i in 0..GRID_WIDTH {
-        for j in 0..GRID_HEIGHT {
-            let pos = offset + Vec3::new(i as f32 * TILE_SIZE, j as f32 * TILE_SIZE, 0.0);
-
-            let tile = Tile::new(i, j);
-
-            let color = if tile.is_even() {
-                tile_colours.even_grass
-            } else {
-                tile_colours.odd_grass
-            };
-
-            let tile_ent = commands
-                .spawn_bundle(SpriteBundle {
-                    sprite: Sprite {
-                        color,
-                        custom_size: Some(Vec2::splat(TILE_SIZE)),
-                        ..default()
-                    },
-                    transform: Transform::from_translation(pos),
-                    ..default()
-                })
-                .insert(tile)
-                .insert(Interaction::None)
-                .insert(Draggable::new(pos))
-                .insert(Resource::None)
-                .id();
+fn setup_grid(
+    mut commands: Commands,
+    mut grid: ResMut<Grid>,
+    layout: Res<GridLayout>,
+    tile_colours: Res<TileColours>,
+) {
+    *grid = Grid::new(*layout);
 
+    for i in 0..layout.width {
+        for j in 0..layout.height {
+            let tile_ent = spawn_tile(&mut commands, &layout, Tile::new(i, j), &tile_colours);
             grid.tiles.push(tile_ent);
         }
     }
 }
 
+/// Spawns the entity for `tile` at its coords. Doesn't add it to the [`Grid`].
+pub fn spawn_tile(
+    commands: &mut Commands,
+    layout: &GridLayout,
+    tile: Tile,
+    tile_colours: &TileColours,
+) -> Entity {
+    let pos = layout.pos(tile.coords).extend(0.0);
+    let mut sprite = Sprite {
+        custom_size: Some(Vec2::splat(layout.tile_size)),
+        ..default()
+    };
+    tile.update_colour(&mut sprite, tile_colours);
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite,
+            transform: Transform::from_translation(pos),
+            ..default()
+        })
+        .insert(tile)
+        .insert(Interaction::None)
+        .insert(Draggable::new(pos))
+        .insert(Resource::default())
+        .insert(ResourceStack::default())
+        .insert(LevelEntity)
+        .id()
+}
+
 fn clear_interaction(mut q_tiles: Query<&mut Interaction, With<Tile>>) {
     for mut interaction in q_tiles.iter_mut() {
         // clear all interactions
@@ -370,7 +572,7 @@ fn clear_interaction(mut q_tiles: Query<&mut Interaction, With<Tile>>) {
     }
 }
 
-fn check_interaction(
+pub fn check_interaction(
     mut q_tiles: Query<&mut Interaction, With<Tile>>,
     grid: Res<Grid>,
     mouse: Res<MouseWorldPos>,
@@ -382,12 +584,9 @@ fn check_interaction(
     let hovered = grid.get_vec2(mouse.0);
     if let Some(ent) = hovered {
         if let Ok(mut interaction) = q_tiles.get_mut(ent) {
+            // clicks are handled by selection and swapping
             if *interaction == Interaction::None {
-                if left_click {
-                    *interaction = Interaction::Clicked;
-                } else {
-                    *interaction = Interaction::Hovered;
-                }
+                *interaction = Interaction::Hovered;
             }
         }
     } else if left_click {
@@ -399,32 +598,34 @@ fn check_interaction(
     }
 }
 
-/// Handles the interaction
+/// Colours tiles by their interaction
 ///
-/// `Clicked` adds [`Selected`]<br />
+/// [`Selection`] uses the select colour<br />
 /// `Hovered` changes the colour to a highlight<br />
 /// `None` changes the colour back to its base   
-fn tile_interaction(
-    mut commands: Commands,
+pub fn tile_interaction(
     mut q_interaction: Query<
-        (Entity, &Interaction, &mut Sprite, &Tile),
-        (Without<TerritoryFlipper>, Without<Selection>),
+        (&Interaction, &mut Sprite, &Tile, Option<&Selection>),
+        (Without<TerritoryFlipper>, Without<SwapTween>),
     >,
     tile_colours: Res<TileColours>,
 ) {
-    for (entity, interaction, mut sprite, tile) in q_interaction.iter_mut() {
+    for (interaction, mut sprite, tile, selected) in q_interaction.iter_mut() {
         // Without<ColourChanger> to skip tiles that are currently animating
-        match *interaction {
-            Interaction::Clicked => {
-                sprite.color = tile_colours.select_color;
-                commands.entity(entity).insert(Selection);
-            }
-            Interaction::Hovered => {
-                sprite.color = tile_colours.hover_color;
-            }
-            Interaction::None => {
-                tile.update_colour(&mut sprite, &tile_colours);
+        let highlight = if selected.is_some() {
+            Some(tile_colours.select_color)
+        } else if *interaction != Interaction::None {
+            Some(tile_colours.hover_color)
+        } else {
+            None
+        };
+        match highlight {
+            Some(colour) => {
+                if sprite.color != colour {
+                    sprite.color = colour;
+                }
             }
+            None => tile.update_colour(&mut sprite, &tile_colours),
         }
     }
 }
@@ -447,23 +648,96 @@ fn gen_flow_field(
     mouse: Res<MouseWorldPos>,
     grid: Res<Grid>,
     q_tiles: Query<&mut Tile>,
+    mut goal: ResMut<FlowFieldGoal>,
 ) {
     if keyboard.just_pressed(KeyCode::F) {
-        let mut dest = Coords::from_vec2(mouse.0);
-
         // clamp in range
-        dest.x = dest.x.clamp(0, GRID_WIDTH - 1);
-        dest.y = dest.y.clamp(0, GRID_HEIGHT - 1);
-        // dest.x = dest.x.min(GRID_WIDTH-1);
-        // dest.y = dest.y.min(GRID_HEIGHT-1);
+        let dest = grid.layout().clamped_coords(mouse.0);
 
+        goal.0 = Some(dest);
         generate_flow_field_grid(dest, grid, q_tiles);
     }
 }
 
+/// Sent when a wall or tower on `tile` runs out of health.
+/// Each structure cleans up its own components.
+pub struct StructureDestroyedEvent {
+    pub tile: Entity,
+}
+
+/// Takes the wall or tower off `tile` without it being destroyed, e.g. when it's sold.
+pub struct RemoveStructureEvent {
+    pub tile: Entity,
+}
+
+fn destroy_structures(
+    mut commands: Commands,
+    mut q_structures: Query<(Entity, &mut Tile, &Health, Option<&HealthBar>), Changed<Health>>,
+    mut ev_destroyed: EventWriter<StructureDestroyedEvent>,
+    mut ev_recalculate: EventWriter<RecalculateFlowFieldEvent>,
+) {
+    for (entity, mut tile, health, bar) in &mut q_structures {
+        if !health.is_dead() {
+            continue;
+        }
+        clear_structure(
+            &mut commands,
+            entity,
+            &mut tile,
+            bar,
+            &mut ev_destroyed,
+            &mut ev_recalculate,
+        );
+    }
+}
+
+fn remove_structures(
+    mut commands: Commands,
+    mut ev_remove: EventReader<RemoveStructureEvent>,
+    mut q_structures: Query<(&mut Tile, Option<&HealthBar>), With<Health>>,
+    mut ev_destroyed: EventWriter<StructureDestroyedEvent>,
+    mut ev_recalculate: EventWriter<RecalculateFlowFieldEvent>,
+) {
+    for ev in ev_remove.iter() {
+        if let Ok((mut tile, bar)) = q_structures.get_mut(ev.tile) {
+            clear_structure(
+                &mut commands,
+                ev.tile,
+                &mut tile,
+                bar,
+                &mut ev_destroyed,
+                &mut ev_recalculate,
+            );
+        }
+    }
+}
+
+fn clear_structure(
+    commands: &mut Commands,
+    entity: Entity,
+    tile: &mut Tile,
+    bar: Option<&HealthBar>,
+    ev_destroyed: &mut EventWriter<StructureDestroyedEvent>,
+    ev_recalculate: &mut EventWriter<RecalculateFlowFieldEvent>,
+) {
+    tile.cost = tile.base_cost;
+    if let Some(bar) = bar {
+        bar.despawn(commands);
+    }
+    commands
+        .entity(entity)
+        .remove::<Health>()
+        .remove::<HealthBar>();
+
+    ev_destroyed.send(StructureDestroyedEvent { tile: entity });
+    ev_recalculate.send(RecalculateFlowFieldEvent);
+}
+
 pub struct ChangeAllegianceEvent {
     pub center_coords: Coords,
     pub range: u32,
+    // cycles to the next TileType if None
+    pub to: Option<TileType>,
 }
 
 fn change_allegiance(
@@ -477,6 +751,11 @@ fn change_allegiance(
             let neighbours = grid.get_diamond_ring(ev.center_coords, i as usize);
             for n in neighbours.iter().flatten() {
                 if let Ok(mut tile) = q_tiles.get_mut(*n) {
+                    if let Some(to) = ev.to {
+                        tile.tile_type = to;
+                        commands.entity(*n).insert(TerritoryFlipper::new(10 * i));
+                        continue;
+                    }
                     match tile.tile_type {
                         TileType::Neutral => {
                             tile.tile_type = TileType::Friendly;
@@ -501,6 +780,8 @@ pub struct TerritoryGrabber {
     timer: Timer,
     // my allegiance to refresh instead of cycling.
     // todo
+    // whether it's flipped any territory yet
+    grabbed: bool,
 }
 
 impl TerritoryGrabber {
@@ -510,7 +791,15 @@ impl TerritoryGrabber {
         // if it's exact, I need grab_territory to run after whatever system
         // that inserts the component
         timer.tick(Duration::from_secs_f32(9.9));
-        TerritoryGrabber { range, timer }
+        TerritoryGrabber {
+            range,
+            timer,
+            grabbed: false,
+        }
+    }
+
+    pub fn has_grabbed(&self) -> bool {
+        self.grabbed
     }
 }
 
@@ -521,10 +810,12 @@ fn grab_territory(
 ) {
     for (tile, mut grabber) in &mut q_grabber {
         if grabber.timer.tick(time.delta()).just_finished() {
+            grabber.grabbed = true;
             // send
             ev_allegiance.send(ChangeAllegianceEvent {
                 center_coords: tile.coords,
                 range: grabber.range,
+                to: None,
             });
         }
     }
@@ -543,7 +834,7 @@ fn grab_territory(
 // enemy code ignores tiles with the colour changer too
 
 #[derive(Component)]
-struct TerritoryFlipper {
+pub struct TerritoryFlipper {
     animation_timer: Timer,
     start_frame: u32,
     current_frame: u32,
@@ -610,3 +901,124 @@ fn territory_flip_animation(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xy(coords: Coords) -> (usize, usize) {
+        (coords.x, coords.y)
+    }
+
+    #[test]
+    fn coords_and_pos_round_trip() {
+        let layout = GridLayout {
+            width: 7,
+            height: 4,
+            tile_size: 24.0,
+        };
+        for x in 0..layout.width {
+            for y in 0..layout.height {
+                let pos = layout.pos(Coords { x, y });
+                assert_eq!(xy(layout.coords(pos)), (x, y));
+                // anywhere inside the tile, not just the centre
+                let corner = pos + Vec2::splat(layout.tile_size * 0.49);
+                assert_eq!(xy(layout.coords(corner)), (x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn grid_is_centred_on_the_origin() {
+        let layout = GridLayout::default();
+        let bottom_left = layout.pos(Coords { x: 0, y: 0 });
+        let top_right = layout.pos(Coords {
+            x: layout.width - 1,
+            y: layout.height - 1,
+        });
+        assert_eq!(bottom_left, -top_right);
+    }
+
+    #[test]
+    fn coords_off_the_grid() {
+        let layout = GridLayout {
+            width: 3,
+            height: 2,
+            tile_size: 10.0,
+        };
+        let far_left = Vec2::new(-100.0, 0.0);
+        let far_right = Vec2::new(100.0, 0.0);
+
+        // off the left or bottom clamps to 0
+        assert_eq!(layout.coords(far_left).x, 0);
+        assert!(layout.tile_at(far_left).is_none());
+        // off the right or top doesn't
+        assert!(!layout.contains(layout.coords(far_right).x, 0));
+        assert!(layout.tile_at(far_right).is_none());
+        assert_eq!(xy(layout.clamped_coords(far_right)), (2, 1));
+    }
+
+    #[test]
+    fn index_and_xy_round_trip() {
+        let layout = GridLayout {
+            width: 5,
+            height: 3,
+            tile_size: 32.0,
+        };
+        let mut seen = vec![false; layout.tile_count()];
+        for x in 0..layout.width {
+            for y in 0..layout.height {
+                let index = layout.index(x, y);
+                assert!(!seen[index]);
+                seen[index] = true;
+                assert_eq!(layout.xy(index), (x, y));
+            }
+        }
+    }
+
+    fn numbered_grid(layout: GridLayout) -> Grid {
+        let mut grid = Grid::new(layout);
+        // each tile's entity is its index
+        grid.tiles = (0..layout.tile_count() as u32)
+            .map(Entity::from_raw)
+            .collect();
+        grid
+    }
+
+    fn line(grid: &Grid, a: (usize, usize), b: (usize, usize)) -> Vec<(usize, usize)> {
+        grid.get_line(Coords { x: a.0, y: a.1 }, Coords { x: b.0, y: b.1 })
+            .into_iter()
+            .map(|entity| grid.layout().xy(entity.id() as usize))
+            .collect()
+    }
+
+    #[test]
+    fn line_steps_one_tile_at_a_time() {
+        let grid = numbered_grid(GridLayout::default());
+        for (a, b) in [
+            ((0, 0), (5, 2)),
+            ((7, 1), (2, 9)),
+            ((4, 4), (4, 0)),
+            ((3, 3), (3, 3)),
+        ] {
+            let tiles = line(&grid, a, b);
+            assert_eq!(tiles.first(), Some(&a));
+            assert_eq!(tiles.last(), Some(&b));
+            // no diagonal steps, so a wall along it has no gaps
+            for pair in tiles.windows(2) {
+                let (x0, y0) = pair[0];
+                let (x1, y1) = pair[1];
+                assert_eq!(x0.abs_diff(x1) + y0.abs_diff(y1), 1, "{:?} to {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn straight_line_is_every_tile_between() {
+        let grid = numbered_grid(GridLayout::default());
+        assert_eq!(
+            line(&grid, (1, 2), (4, 2)),
+            [(1, 2), (2, 2), (3, 2), (4, 2)]
+        );
+    }
+}
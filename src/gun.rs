@@ -1,10 +1,17 @@
 use std::time::Duration;
 
 use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
-// use bevy_prototype_lyon::prelude::*;
-use bevy_rapier2d::prelude::*;
-
-use crate::{enemy::Enemy, health::Health};
+use bevy_prototype_lyon::prelude::{shapes, DrawMode, GeometryBuilder, StrokeMode};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    enemy::{Enemy, TargetLayers},
+    health::Health,
+    level::LevelEntity,
+    resource_container::ResourceKind,
+    spatial::EnemySpatialIndex,
+    visuals::{VisualsCache, EXPLOSION_COLOUR},
+};
 
 pub struct GunPlugin;
 
@@ -15,6 +22,8 @@ impl Plugin for GunPlugin {
             .add_system(tick_guns)
             .add_system(spawn_bomb_visuals.before(tick_explosions))
             .add_system(tick_explosions)
+            .add_system(resolve_instant_hits)
+            .add_system(fade_hit_visuals)
             .add_system(update_killcount);
     }
 }
@@ -32,9 +41,108 @@ struct KillEvent {
 
 pub enum GunType {
     Pistol,
-    Shotgun,
+    Shotgun(SpreadInfo),
     Burst(BurstInfo),
     Bomb,
+    Hitscan(HitscanInfo),
+    Beam(BeamInfo),
+    Chain(ChainInfo),
+}
+
+// how the pellets of a shotgun fan out
+#[derive(Copy, Clone, Debug)]
+pub struct SpreadInfo {
+    pub pellets: u32,
+    // angle between the outermost pellets in degrees
+    pub spread: f32,
+    // max random offset added to each pellet in degrees
+    // 0.0 for the same pattern every shot
+    pub jitter: f32,
+}
+
+impl SpreadInfo {
+    /// Fires at least 1 pellet. Negative angles are treated as 0.0.
+    pub fn new(pellets: u32, spread: f32, jitter: f32) -> Self {
+        SpreadInfo {
+            pellets: pellets.max(1),
+            spread: spread.max(0.0),
+            jitter: jitter.max(0.0),
+        }
+    }
+
+    /// Directions for each pellet, fanned out evenly around `aim`.
+    fn pellet_dirs(&self, aim: Vec2, rng: &mut StdRng) -> Vec<Vec2> {
+        (0..self.pellets)
+            .map(|i| {
+                let mut degrees = if self.pellets > 1 {
+                    // -spread/2 to spread/2
+                    self.spread * (i as f32 / (self.pellets - 1) as f32 - 0.5)
+                } else {
+                    0.0
+                };
+                if self.jitter > 0.0 {
+                    degrees += rng.gen_range(-self.jitter..=self.jitter);
+                }
+                rotate_degrees(aim, degrees)
+            })
+            .collect()
+    }
+}
+
+/// Rotates `dir` counter-clockwise by `degrees`.
+fn rotate_degrees(dir: Vec2, degrees: f32) -> Vec2 {
+    let x = f32::cos(degrees.to_radians());
+    let y = f32::sin(degrees.to_radians());
+    dir.rotate(Vec2::new(x, y))
+}
+
+// instant hit guns don't spawn a Bullet
+// they use the gun's bullet damage so kill upgrades still apply
+#[derive(Copy, Clone, Debug)]
+pub struct HitscanInfo {
+    pub range: f32,
+}
+
+impl HitscanInfo {
+    pub fn new(range: f32) -> Self {
+        HitscanInfo { range }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BeamInfo {
+    pub range: f32,
+    // extra damage per second spent on the same target
+    pub ramp_per_second: f32,
+    pub max_bonus: u32,
+}
+
+impl BeamInfo {
+    pub fn new(range: f32, ramp_per_second: f32, max_bonus: u32) -> Self {
+        BeamInfo {
+            range,
+            ramp_per_second,
+            max_bonus,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ChainInfo {
+    pub range: f32,
+    // how many extra enemies it can jump to after the first
+    pub jumps: u32,
+    pub jump_radius: f32,
+}
+
+impl ChainInfo {
+    pub fn new(range: f32, jumps: u32, jump_radius: f32) -> Self {
+        ChainInfo {
+            range,
+            jumps,
+            jump_radius,
+        }
+    }
 }
 
 // data the gun stores for how it operates
@@ -86,6 +194,28 @@ pub struct BasicGunInfo {
     pub clip_size: u32,
     pub time_to_reload: f32,
     pub gun_type: GunType,
+    // seed for random spread. None to seed from entropy
+    pub seed: Option<u64>,
+    pub aim: AimMode,
+    // anti-air guns can hit flying enemies
+    pub targets: TargetLayers,
+    // None reloads for free
+    pub ammo: Option<AmmoCost>,
+}
+
+/// What a gun uses up each time it reloads.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AmmoCost {
+    pub kind: ResourceKind,
+    pub per_reload: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AimMode {
+    // shoot where the target is now
+    Direct,
+    // shoot where the target will be when the bullet gets there
+    Lead,
 }
 
 impl Default for BasicGunInfo {
@@ -96,6 +226,10 @@ impl Default for BasicGunInfo {
             clip_size: 6,
             time_to_reload: 1.0,
             gun_type: GunType::Pistol,
+            seed: None,
+            aim: AimMode::Direct,
+            targets: TargetLayers::GROUND,
+            ammo: None,
         }
     }
 }
@@ -111,6 +245,21 @@ pub struct Gun {
     state: GunState,
     gun_type: GunType,
     kill_count: u32,
+    // beam target and when the beam first landed on it.
+    // Cleared when the target changes, dies or the gun reloads
+    beam_target: Option<(Entity, f64)>,
+    rng: StdRng,
+    aim: AimMode,
+    ammo: Option<AmmoCost>,
+    // reload timer is done but there's been nothing to reload with
+    waiting_for_ammo: bool,
+}
+
+fn rng_from_seed(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
 }
 
 impl Gun {
@@ -131,12 +280,17 @@ impl Gun {
             state: GunState::Ready,
             gun_type,
             kill_count: 0,
+            beam_target: None,
+            rng: rng_from_seed(None),
+            aim: AimMode::Direct,
+            ammo: None,
+            waiting_for_ammo: false,
         }
     }
 
     pub fn from_basic_gun_info(gun_info: BasicGunInfo) -> Self {
         Gun {
-            bullet: gun_info.bullet,
+            bullet: gun_info.bullet.update_targets(gun_info.targets),
             timer_between_shots: Timer::from_seconds(gun_info.time_between_shots, true),
             current_shots: gun_info.clip_size,
             clip_size: gun_info.clip_size,
@@ -144,9 +298,29 @@ impl Gun {
             state: GunState::Ready,
             gun_type: gun_info.gun_type,
             kill_count: 0,
+            beam_target: None,
+            rng: rng_from_seed(gun_info.seed),
+            aim: gun_info.aim,
+            ammo: gun_info.ammo,
+            waiting_for_ammo: false,
+        }
+    }
+
+    /// One more damage per hit.
+    pub fn upgrade_damage(&mut self) {
+        match self.bullet.end_behaviour {
+            EndBehaviour::Explode(mut info) => {
+                info.damage += 1;
+                // upgrade the damage of the bomb instead of the bullet
+                self.bullet.end_behaviour = EndBehaviour::Explode(info);
+            }
+            _ => {
+                self.bullet.damage += 1;
+            }
         }
     }
 
+    #[allow(clippy::collapsible_match)]
     fn tick(&mut self, delta: Duration, commands: &mut Commands) {
         match self.state {
             GunState::ShotCooldown => {
@@ -155,8 +329,14 @@ impl Gun {
                 }
             }
             GunState::Reloading => {
-                if self.reload_timer.tick(delta).just_finished() {
-                    self.reload();
+                if self.waiting_for_ammo {
+                    // stays reloading until load_ammo
+                } else if self.reload_timer.tick(delta).just_finished() {
+                    if self.ammo.is_some() {
+                        self.waiting_for_ammo = true;
+                    } else {
+                        self.reload();
+                    }
                 }
             }
             GunState::Firing(mut b) => {
@@ -205,19 +385,12 @@ impl Gun {
                 GunType::Pistol => {
                     self.bullet.spawn(commands, pos, target_pos, parent_entity);
                 }
-                GunType::Shotgun => {
-                    self.bullet.spawn(
-                        commands,
-                        pos + Vec3::new(0.0, 10.0, 0.0),
-                        target_pos,
-                        parent_entity,
-                    );
-                    self.bullet.spawn(
-                        commands,
-                        pos + Vec3::new(0.0, -10.0, 0.0),
-                        target_pos,
-                        parent_entity,
-                    );
+                GunType::Shotgun(spread) => {
+                    let aim = target_pos - pos.truncate();
+                    for dir in spread.pellet_dirs(aim, &mut self.rng) {
+                        self.bullet
+                            .spawn(commands, pos, pos.truncate() + dir, parent_entity);
+                    }
                 }
                 GunType::Burst(b) => {
                     self.bullet.spawn(commands, pos, target_pos, parent_entity);
@@ -234,21 +407,116 @@ impl Gun {
                     // self.bullet = b;
                     self.bullet.spawn(commands, pos, target_pos, parent_entity);
                 }
+                GunType::Hitscan(info) => {
+                    InstantHit::spawn(
+                        commands,
+                        InstantHitKind::Hitscan(info),
+                        pos,
+                        target_pos,
+                        self.bullet.damage,
+                        self.bullet.targets,
+                        parent_entity,
+                    );
+                }
+                GunType::Beam(info) => {
+                    InstantHit::spawn(
+                        commands,
+                        InstantHitKind::Beam(info),
+                        pos,
+                        target_pos,
+                        self.bullet.damage,
+                        self.bullet.targets,
+                        parent_entity,
+                    );
+                }
+                GunType::Chain(info) => {
+                    InstantHit::spawn(
+                        commands,
+                        InstantHitKind::Chain(info),
+                        pos,
+                        target_pos,
+                        self.bullet.damage,
+                        self.bullet.targets,
+                        parent_entity,
+                    );
+                }
             }
 
             self.current_shots -= 1;
             if self.current_shots == 0 {
                 self.state = GunState::Reloading;
+                // the beam goes off while reloading, so the ramp starts over
+                self.beam_target = None;
             } else {
                 self.state = GunState::ShotCooldown;
             }
         }
     }
 
+    pub fn aim_mode(&self) -> AimMode {
+        self.aim
+    }
+
+    /// Where to shoot from `pos` to hit a target at `target_pos` moving at `target_vel`.
+    /// Returns `target_pos` if the gun doesn't lead or the bullet can't catch the target.
+    pub fn lead_target(&self, pos: Vec2, target_pos: Vec2, target_vel: Vec2) -> Vec2 {
+        if self.aim == AimMode::Direct || target_vel == Vec2::ZERO {
+            return target_pos;
+        }
+
+        let time = match self.gun_type {
+            // instant hits don't need to lead
+            GunType::Hitscan(_) | GunType::Beam(_) | GunType::Chain(_) => None,
+            _ => match self.bullet.movement {
+                Movement::Straight(_) => {
+                    intercept_time(target_pos - pos, target_vel, self.bullet.speed, 0.0)
+                }
+                Movement::Arc { .. } => {
+                    // lifetime of an arc is a line from (min_dist, min_time) to (max_dist, max_time)
+                    // so the distance it travels in time t is
+                    // dist = t * speed + offset
+                    let speed = (ARC_MAX_DIST - ARC_MIN_DIST) / (ARC_MAX_TIME - ARC_MIN_TIME);
+                    let offset = ARC_MIN_DIST - speed * ARC_MIN_TIME;
+                    intercept_time(target_pos - pos, target_vel, speed, offset)
+                        // outside of this the arc is clamped and won't land where we want
+                        .filter(|t| (ARC_MIN_TIME..=ARC_MAX_TIME).contains(t))
+                }
+            },
+        };
+
+        match time {
+            Some(t) => target_pos + target_vel * t,
+            None => target_pos,
+        }
+    }
+
     fn reload(&mut self) {
         self.current_shots = self.clip_size;
         self.state = GunState::Ready;
     }
+
+    pub fn uses_ammo(&self) -> bool {
+        self.ammo.is_some()
+    }
+
+    /// What it needs to finish reloading, if it's stuck waiting for it.
+    pub fn ammo_needed(&self) -> Option<AmmoCost> {
+        if self.waiting_for_ammo {
+            self.ammo
+        } else {
+            None
+        }
+    }
+
+    /// Finishes a reload that was waiting on ammo.
+    pub fn load_ammo(&mut self) {
+        self.waiting_for_ammo = false;
+        self.reload();
+    }
+
+    pub fn is_out_of_ammo(&self) -> bool {
+        self.waiting_for_ammo
+    }
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -263,7 +531,53 @@ enum GunState {
 pub enum EndBehaviour {
     None,
     Explode(ExplosionInfo),
-    Split(u32),
+    Split(SplitInfo),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SplitInfo {
+    // how many more times the bullets split
+    generations: u32,
+    // each split spawns 2 bullets this many degrees either side
+    degrees: f32,
+}
+
+impl SplitInfo {
+    pub fn new(generations: u32, degrees: f32) -> Self {
+        SplitInfo {
+            generations,
+            degrees,
+        }
+    }
+}
+
+// damage drops off linearly between start and end
+#[derive(Copy, Clone, Debug)]
+pub struct DamageFalloff {
+    start: f32,
+    end: f32,
+    min_damage: u32,
+}
+
+impl DamageFalloff {
+    /// `end` is pushed out past `start` if it isn't already.
+    pub fn new(start: f32, end: f32, min_damage: u32) -> Self {
+        DamageFalloff {
+            start,
+            // needs some distance to fall off over
+            end: end.max(start + 1.0),
+            min_damage,
+        }
+    }
+
+    fn apply(&self, damage: u32, distance: f32) -> u32 {
+        if damage <= self.min_damage {
+            return damage;
+        }
+        let percent = ((distance - self.start) / (self.end - self.start)).clamp(0.0, 1.0);
+        let lost = ((damage - self.min_damage) as f32 * percent).round() as u32;
+        damage - lost
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -286,10 +600,17 @@ struct ExplosionComponent {
     parent_entity: Entity,
     pos: Vec3,
     radius: f32,
+    targets: TargetLayers,
 }
 
 impl ExplosionComponent {
-    fn new(damage: u32, parent_entity: Entity, pos: Vec3, radius: f32) -> Self {
+    fn new(
+        damage: u32,
+        parent_entity: Entity,
+        pos: Vec3,
+        radius: f32,
+        targets: TargetLayers,
+    ) -> Self {
         ExplosionComponent {
             damage,
             damage_timer: Timer::from_seconds(0.2, false),
@@ -297,6 +618,7 @@ impl ExplosionComponent {
             parent_entity,
             pos,
             radius,
+            targets,
         }
     }
 }
@@ -311,6 +633,9 @@ pub struct Bullet {
     self_entity: Option<Entity>,
     parent_entity: Option<Entity>,
     end_behaviour: EndBehaviour,
+    falloff: Option<DamageFalloff>,
+    start_pos: Vec2,
+    targets: TargetLayers,
 }
 
 impl Bullet {
@@ -325,6 +650,9 @@ impl Bullet {
             self_entity: None,
             parent_entity: None,
             end_behaviour,
+            falloff: None,
+            start_pos: Vec2::ZERO,
+            targets: TargetLayers::GROUND,
         }
     }
 
@@ -342,6 +670,9 @@ impl Bullet {
             self_entity: None,
             parent_entity: None,
             end_behaviour,
+            falloff: None,
+            start_pos: Vec2::ZERO,
+            targets: TargetLayers::GROUND,
         }
     }
 
@@ -371,6 +702,9 @@ impl Bullet {
             self_entity: Some(self_entity),
             parent_entity: Some(parent_entity),
             end_behaviour,
+            falloff: None,
+            start_pos: pos.truncate(),
+            targets: TargetLayers::GROUND,
         }
         .update_arc(pos, target_pos)
     }
@@ -394,10 +728,10 @@ impl Bullet {
     }
 
     fn update_arc(mut self, pos: Vec3, target_pos: Vec2) -> Self {
-        let min_dist = 1.0;
-        let max_dist = 100.0;
-        let min_time = 0.4;
-        let max_time = 2.0;
+        let min_dist = ARC_MIN_DIST;
+        let max_dist = ARC_MAX_DIST;
+        let min_time = ARC_MIN_TIME;
+        let max_time = ARC_MAX_TIME;
 
         let dir_to_target = target_pos - pos.truncate();
         // min and max range of the bomb
@@ -427,6 +761,36 @@ impl Bullet {
         self
     }
 
+    pub fn with_falloff(mut self, falloff: DamageFalloff) -> Self {
+        self.falloff = Some(falloff);
+        self
+    }
+
+    fn update_falloff(mut self, falloff: Option<DamageFalloff>, start_pos: Vec2) -> Self {
+        self.falloff = falloff;
+        self.start_pos = start_pos;
+        self
+    }
+
+    /// Damage after falloff for the distance travelled to `pos`.
+    fn damage_at(&self, pos: Vec2) -> u32 {
+        self.damage_from(self.damage, pos)
+    }
+
+    /// `damage` after falloff for the distance travelled to `pos`.
+    /// Explosions fall off the same as the bullet that made them.
+    fn damage_from(&self, damage: u32, pos: Vec2) -> u32 {
+        match self.falloff {
+            Some(falloff) => falloff.apply(damage, self.start_pos.distance(pos)),
+            None => damage,
+        }
+    }
+
+    fn update_targets(mut self, targets: TargetLayers) -> Self {
+        self.targets = targets;
+        self
+    }
+
     fn update_entity(mut self, entity: Entity) -> Self {
         self.self_entity = Some(entity);
         self
@@ -452,6 +816,7 @@ impl Bullet {
                 },
                 ..default()
             })
+            .insert(LevelEntity)
             .id();
 
         if matches!(
@@ -463,14 +828,17 @@ impl Bullet {
             }
         ) {
             // bombs don't need collision when travelling in the arc.
-            commands.entity(ent).insert(Bullet::new_arc_complete(
-                self.damage,
-                pos,
-                target_pos,
-                self.end_behaviour,
-                ent,
-                parent,
-            ));
+            commands.entity(ent).insert(
+                Bullet::new_arc_complete(
+                    self.damage,
+                    pos,
+                    target_pos,
+                    self.end_behaviour,
+                    ent,
+                    parent,
+                )
+                .update_targets(self.targets),
+            );
         } else {
             commands
                 .entity(ent)
@@ -481,11 +849,10 @@ impl Bullet {
                     Bullet::new(self.damage, self.end_behaviour)
                         .update_entity(ent)
                         .update_parent(parent)
+                        .update_falloff(self.falloff, pos.truncate())
+                        .update_targets(self.targets)
                         .update_dir((target_pos - pos.truncate()).normalize_or_zero()),
-                )
-                .insert(RigidBody::Dynamic)
-                .insert(Collider::ball(8.0))
-                .insert(Sensor);
+                );
         }
     }
 
@@ -498,6 +865,15 @@ impl Bullet {
                 // let b = Bullet::new(Vec2::ZERO, info.damage, EndBehaviour::None);
                 // b.spawn(commands, pos, Vec2::ZERO, self.parent_entity.unwrap());
 
+                // arcs land on the ground. They can't hit flyers
+                let targets = match self.movement {
+                    Movement::Straight(_) => self.targets,
+                    Movement::Arc { .. } => TargetLayers {
+                        air: false,
+                        ..self.targets
+                    },
+                };
+
                 // spawn a bomb
                 commands
                     .spawn_bundle(SpatialBundle {
@@ -505,39 +881,36 @@ impl Bullet {
                         transform: Transform::from_translation(pos),
                         ..default()
                     })
+                    .insert(LevelEntity)
                     .insert(ExplosionComponent::new(
-                        info.damage,
+                        self.damage_from(info.damage, pos.truncate()),
                         self.parent_entity.unwrap(),
                         pos,
                         info.radius,
-                    ))
-                    .insert(Collider::ball(info.radius))
-                    .insert(Sensor);
+                        targets,
+                    ));
 
                 // remove the bullet that's life has ended
                 self.despawn(commands);
             }
-            EndBehaviour::Split(num) => {
-                if num > 0 {
-                    // println!("Split! {:?}", num);
-                    let b = Bullet::new(self.damage, EndBehaviour::Split(num - 1));
-                    // rotate some degrees
-                    let degrees = 10.0f32;
-                    // deg to rad = 0.01745329251
-                    let x = f32::cos(degrees.to_radians());
-                    let y = f32::sin(degrees.to_radians());
-                    b.spawn(
-                        commands,
-                        pos,
-                        self.dir.rotate(Vec2::new(x, y)),
-                        self.parent_entity.unwrap(),
-                    );
-                    b.spawn(
-                        commands,
-                        pos,
-                        self.dir.rotate(Vec2::new(x, -y)),
-                        self.parent_entity.unwrap(),
+            EndBehaviour::Split(info) => {
+                if info.generations > 0 {
+                    // println!("Split! {:?}", info.generations);
+                    let mut b = Bullet::new(
+                        self.damage,
+                        EndBehaviour::Split(SplitInfo {
+                            generations: info.generations - 1,
+                            ..info
+                        }),
                     );
+                    // children fall off the same way
+                    b.falloff = self.falloff;
+                    b.targets = self.targets;
+                    for degrees in [info.degrees, -info.degrees] {
+                        // spawn takes a target pos, not a dir
+                        let target_pos = pos.truncate() + rotate_degrees(self.dir, degrees);
+                        b.spawn(commands, pos, target_pos, self.parent_entity.unwrap());
+                    }
                 }
                 self.despawn(commands);
             }
@@ -551,6 +924,36 @@ impl Bullet {
     }
 }
 
+// range and flight time of arcing bullets
+const ARC_MIN_DIST: f32 = 1.0;
+const ARC_MAX_DIST: f32 = 100.0;
+const ARC_MIN_TIME: f32 = 0.4;
+const ARC_MAX_TIME: f32 = 2.0;
+
+/// Earliest time a bullet can meet a target that starts `offset` away moving at `target_vel`.
+///
+/// The bullet covers `speed * t + start_dist` in time `t`.
+/// Solves |offset + target_vel * t| = speed * t + start_dist
+fn intercept_time(offset: Vec2, target_vel: Vec2, speed: f32, start_dist: f32) -> Option<f32> {
+    // square both sides and collect the t terms
+    let a = target_vel.length_squared() - speed * speed;
+    let b = 2.0 * (offset.dot(target_vel) - speed * start_dist);
+    let c = offset.length_squared() - start_dist * start_dist;
+
+    roots::find_roots_quadratic(a, b, c)
+        .as_ref()
+        .iter()
+        .copied()
+        .filter(|t| *t >= 0.0)
+        .fold(None, |earliest: Option<f32>, t| match earliest {
+            Some(e) if e <= t => Some(e),
+            _ => Some(t),
+        })
+}
+
+// bullets are 16x16
+const BULLET_RADIUS: f32 = 8.0;
+
 #[derive(Debug)]
 enum Movement {
     Straight(Vec2),
@@ -563,13 +966,13 @@ enum Movement {
 
 fn tick_bullets(
     mut commands: Commands,
-    mut q_bullets: Query<(Entity, &mut Transform, &mut Bullet)>,
-    rapier_context: Res<RapierContext>,
-    mut q_enemies: Query<(Entity, &mut Enemy, &mut Health)>,
+    mut q_bullets: Query<(&mut Transform, &mut Bullet)>,
+    index: Res<EnemySpatialIndex>,
+    mut q_enemies: Query<&mut Health, With<Enemy>>,
     mut ev_kill: EventWriter<KillEvent>,
     time: Res<Time>,
 ) {
-    for (bullet_ent, mut trans, mut bullet) in q_bullets.iter_mut() {
+    for (mut trans, mut bullet) in q_bullets.iter_mut() {
         match bullet.movement {
             Movement::Straight(dir) => {
                 trans.translation += dir.extend(0.0) * bullet.speed * time.delta_seconds();
@@ -593,30 +996,32 @@ fn tick_bullets(
         // trans.translation += bullet.dir.extend(0.0) * bullet.speed * time.delta_seconds();
 
         // does it hit anything?
-        let collisions = rapier_context.intersections_with(bullet_ent);
+        // bombs don't collide when travelling in the arc
+        let collisions = match bullet.movement {
+            Movement::Straight(_) => {
+                index.enemies_in_radius(trans.translation.truncate(), BULLET_RADIUS, bullet.targets)
+            }
+            Movement::Arc { .. } => Vec::new(),
+        };
         let mut hit_something = false;
-        for (a, b, hit) in collisions {
-            if hit {
-                let enemy_ent = if a == bullet_ent { b } else { a };
-
-                if let Ok((_e_ent, _enemy, mut health)) = q_enemies.get_mut(enemy_ent) {
-                    // enemy.take_damage();
-                    // println!("Killed something");
-                    // println!(
-                    //     "Killed something. Bullet: {:?}, Parent: {:?}",
-                    //     bullet_ent,
-                    //     bullet.parent_entity.unwrap()
-                    // );
-                    health.take_damage(bullet.damage);
-                    if health.just_died() {
-                        ev_kill.send(KillEvent {
-                            tower: bullet.parent_entity.unwrap(),
-                        });
-                        // commands.entity(e_ent).despawn_recursive();
-                    }
-
-                    hit_something = true;
+        for (enemy_ent, _enemy_pos) in collisions {
+            if let Ok(mut health) = q_enemies.get_mut(enemy_ent) {
+                // enemy.take_damage();
+                // println!("Killed something");
+                // println!(
+                //     "Killed something. Bullet: {:?}, Parent: {:?}",
+                //     bullet_ent,
+                //     bullet.parent_entity.unwrap()
+                // );
+                health.take_damage(bullet.damage_at(trans.translation.truncate()));
+                if health.just_died() {
+                    ev_kill.send(KillEvent {
+                        tower: bullet.parent_entity.unwrap(),
+                    });
+                    // commands.entity(e_ent).despawn_recursive();
                 }
+
+                hit_something = true;
             }
         }
 
@@ -632,36 +1037,33 @@ fn tick_bullets(
 fn tick_explosions(
     mut commands: Commands,
     mut q_explosions: Query<(Entity, &mut ExplosionComponent)>,
-    rapier_context: Res<RapierContext>,
-    mut q_enemies: Query<(Entity, &mut Enemy, &mut Health)>,
+    index: Res<EnemySpatialIndex>,
+    mut q_enemies: Query<&mut Health, With<Enemy>>,
     mut ev_kill: EventWriter<KillEvent>,
     time: Res<Time>,
 ) {
     for (bomb_ent, mut bomb) in q_explosions.iter_mut() {
-        if bomb.damage_timer.tick(time.delta()).just_finished() {
-            // remove collider
-            commands.entity(bomb_ent).remove::<Collider>();
-            // println!("Bomb safe {:?} {:?}", bomb_ent, bomb.pos);
-        }
+        // only does damage for the first part of the explosion
+        let damaging = !bomb.damage_timer.finished();
+        bomb.damage_timer.tick(time.delta());
         if bomb.visual_timer.tick(time.delta()).just_finished() {
             commands.entity(bomb_ent).despawn_recursive();
             // println!("Bomb dead {:?} {:?}", bomb_ent, bomb.pos);
         }
+        if !damaging {
+            continue;
+        }
 
-        let collisions = rapier_context.intersections_with(bomb_ent);
-        for (a, b, hit) in collisions {
-            if hit {
-                let enemy_ent = if a == bomb_ent { b } else { a };
-
-                if let Ok((_e_ent, _enemy, mut health)) = q_enemies.get_mut(enemy_ent) {
-                    health.take_damage(bomb.damage);
-                    if health.just_died() {
-                        ev_kill.send(KillEvent {
-                            tower: bomb.parent_entity,
-                        });
-                        // enemy.die(&mut commands, e_ent);
-                        // commands.entity(e_ent).despawn_recursive();
-                    }
+        let collisions = index.enemies_in_radius(bomb.pos.truncate(), bomb.radius, bomb.targets);
+        for (enemy_ent, _enemy_pos) in collisions {
+            if let Ok(mut health) = q_enemies.get_mut(enemy_ent) {
+                health.take_damage(bomb.damage);
+                if health.just_died() {
+                    ev_kill.send(KillEvent {
+                        tower: bomb.parent_entity,
+                    });
+                    // enemy.die(&mut commands, e_ent);
+                    // commands.entity(e_ent).despawn_recursive();
                 }
             }
         }
@@ -672,14 +1074,15 @@ fn spawn_bomb_visuals(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    mut cache: ResMut<VisualsCache>,
     q_explosions: Query<(Entity, &ExplosionComponent), Added<ExplosionComponent>>,
 ) {
     for (entity, bomb) in q_explosions.iter() {
         // println!("Spawn bomb visuals {:?}", entity);
         // this should reset the transform that's already there
         commands.entity(entity).insert_bundle(MaterialMesh2dBundle {
-            mesh: meshes.add(shape::Circle::new(bomb.radius).into()).into(),
-            material: materials.add(ColorMaterial::from(Color::ORANGE)),
+            mesh: cache.circle(&mut meshes, bomb.radius),
+            material: cache.material(&mut materials, EXPLOSION_COLOUR),
             transform: Transform::from_translation(bomb.pos),
             ..default()
         });
@@ -689,6 +1092,213 @@ fn spawn_bomb_visuals(
     }
 }
 
+// how wide the line of an instant hit is for hitting things
+const INSTANT_HIT_WIDTH: f32 = 2.0;
+
+#[derive(Copy, Clone, Debug)]
+enum InstantHitKind {
+    Hitscan(HitscanInfo),
+    Beam(BeamInfo),
+    Chain(ChainInfo),
+}
+
+// an instant hit shot. Resolved the frame after it's spawned.
+// like an explosion, but it's removed as soon as the damage is dealt
+#[derive(Component)]
+struct InstantHit {
+    kind: InstantHitKind,
+    start: Vec2,
+    target_pos: Vec2,
+    damage: u32,
+    targets: TargetLayers,
+    parent_entity: Entity,
+}
+
+impl InstantHit {
+    fn spawn(
+        commands: &mut Commands,
+        kind: InstantHitKind,
+        pos: Vec3,
+        target_pos: Vec2,
+        damage: u32,
+        targets: TargetLayers,
+        parent_entity: Entity,
+    ) {
+        commands.spawn().insert(LevelEntity).insert(InstantHit {
+            kind,
+            start: pos.truncate(),
+            target_pos,
+            damage,
+            targets,
+            parent_entity,
+        });
+    }
+
+    /// Where the shot would end if it didn't hit anything.
+    fn end_of_range(&self, range: f32) -> Vec2 {
+        let dir = (self.target_pos - self.start).normalize_or_zero();
+        self.start + dir * range
+    }
+}
+
+// the line left behind by an instant hit
+#[derive(Component)]
+struct HitVisual {
+    lifetime: Timer,
+    start_alpha: f32,
+}
+
+fn spawn_hit_visual(commands: &mut Commands, points: Vec<Vec2>, colour: Color, width: f32) {
+    let shape = shapes::Polygon {
+        points,
+        closed: false,
+    };
+    commands
+        .spawn_bundle(GeometryBuilder::build_as(
+            &shape,
+            DrawMode::Stroke(StrokeMode::new(colour, width)),
+            // above tiles, bullets, and enemies
+            Transform::from_translation(Vec3::new(0.0, 0.0, 0.5)),
+        ))
+        .insert(HitVisual {
+            lifetime: Timer::from_seconds(0.15, false),
+            start_alpha: colour.a(),
+        })
+        .insert(LevelEntity);
+}
+
+fn resolve_instant_hits(
+    mut commands: Commands,
+    q_hits: Query<(Entity, &InstantHit)>,
+    index: Res<EnemySpatialIndex>,
+    mut q_enemies: Query<(&Transform, &mut Health), With<Enemy>>,
+    mut q_guns: Query<&mut Gun>,
+    mut ev_kill: EventWriter<KillEvent>,
+    time: Res<Time>,
+) {
+    for (hit_ent, hit) in &q_hits {
+        // (enemy, damage)
+        let mut damaged = Vec::new();
+
+        match hit.kind {
+            InstantHitKind::Hitscan(info) => {
+                let end = hit.end_of_range(info.range);
+                let end = if let Some((enemy, hit_pos)) =
+                    index.first_enemy_on_segment(hit.start, end, INSTANT_HIT_WIDTH, hit.targets)
+                {
+                    damaged.push((enemy, hit.damage));
+                    hit_pos
+                } else {
+                    end
+                };
+                spawn_hit_visual(&mut commands, vec![hit.start, end], Color::WHITE, 2.0);
+            }
+            InstantHitKind::Beam(info) => {
+                let end = hit.end_of_range(info.range);
+                let mut width = 2.0;
+                let end = if let Some((enemy, hit_pos)) =
+                    index.first_enemy_on_segment(hit.start, end, INSTANT_HIT_WIDTH, hit.targets)
+                {
+                    let mut bonus = 0;
+                    if let Ok(mut gun) = q_guns.get_mut(hit.parent_entity) {
+                        let now = time.seconds_since_startup();
+                        let since = match gun.beam_target {
+                            Some((target, since)) if target == enemy => since,
+                            _ => now,
+                        };
+                        gun.beam_target = Some((enemy, since));
+                        let time_on_target = (now - since) as f32;
+                        bonus =
+                            ((time_on_target * info.ramp_per_second) as u32).min(info.max_bonus);
+                    }
+                    // thicker beam the longer it's on
+                    width += bonus as f32;
+                    damaged.push((enemy, hit.damage + bonus));
+                    hit_pos
+                } else {
+                    if let Ok(mut gun) = q_guns.get_mut(hit.parent_entity) {
+                        gun.beam_target = None;
+                    }
+                    end
+                };
+                spawn_hit_visual(&mut commands, vec![hit.start, end], Color::CYAN, width);
+            }
+            InstantHitKind::Chain(info) => {
+                let end = hit.end_of_range(info.range);
+                let mut points = vec![hit.start];
+                let mut already_hit = Vec::new();
+                if let Some((first, first_pos)) =
+                    index.first_enemy_on_segment(hit.start, end, INSTANT_HIT_WIDTH, hit.targets)
+                {
+                    // jump from the center of each enemy
+                    let mut last_pos = q_enemies
+                        .get(first)
+                        .map(|(trans, _)| trans.translation.truncate())
+                        .unwrap_or(first_pos);
+                    points.push(last_pos);
+                    already_hit.push(first);
+
+                    for _ in 0..info.jumps {
+                        let next =
+                            index.nearest_enemy(last_pos, info.jump_radius, hit.targets, |e| {
+                                !already_hit.contains(&e)
+                                    && matches!(q_enemies.get(e), Ok((_, health)) if !health.is_dead())
+                            });
+                        if let Some((next, next_pos)) = next {
+                            points.push(next_pos);
+                            already_hit.push(next);
+                            last_pos = next_pos;
+                        } else {
+                            break;
+                        }
+                    }
+                } else {
+                    points.push(end);
+                }
+                for enemy in already_hit {
+                    damaged.push((enemy, hit.damage));
+                }
+                spawn_hit_visual(&mut commands, points, Color::YELLOW, 3.0);
+            }
+        }
+
+        for (enemy, damage) in damaged {
+            if let Ok((_, mut health)) = q_enemies.get_mut(enemy) {
+                health.take_damage(damage);
+                if health.just_died() {
+                    ev_kill.send(KillEvent {
+                        tower: hit.parent_entity,
+                    });
+                    // the next target starts from no bonus
+                    if matches!(hit.kind, InstantHitKind::Beam(_)) {
+                        if let Ok(mut gun) = q_guns.get_mut(hit.parent_entity) {
+                            gun.beam_target = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        commands.entity(hit_ent).despawn();
+    }
+}
+
+fn fade_hit_visuals(
+    mut commands: Commands,
+    mut q_visuals: Query<(Entity, &mut DrawMode, &mut HitVisual)>,
+    time: Res<Time>,
+) {
+    for (entity, mut draw_mode, mut visual) in &mut q_visuals {
+        if visual.lifetime.tick(time.delta()).just_finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+        let alpha = visual.start_alpha * visual.lifetime.percent_left();
+        if let DrawMode::Stroke(ref mut stroke) = *draw_mode {
+            stroke.color.set_a(alpha);
+        }
+    }
+}
+
 fn tick_guns(mut commands: Commands, mut q_guns: Query<&mut Gun>, time: Res<Time>) {
     for mut gun in q_guns.iter_mut() {
         gun.tick(time.delta(), &mut commands);
@@ -701,16 +1311,7 @@ fn update_killcount(mut ev_kill: EventReader<KillEvent>, mut q_towers: Query<&mu
             gun.kill_count += 1;
             // println!("Updated killcount: {:?}", gun.kill_count);
             if gun.kill_count % 5 == 0 {
-                match gun.bullet.end_behaviour {
-                    EndBehaviour::Explode(mut info) => {
-                        info.damage += 1;
-                        // upgrade the damage of the bomb instead of the bullet
-                        gun.bullet.end_behaviour = EndBehaviour::Explode(info);
-                    }
-                    _ => {
-                        gun.bullet.damage += 1;
-                    }
-                }
+                gun.upgrade_damage();
                 // gun.bullet.damage += 1;
                 println!("Five kills. Damage up {:?}", gun.bullet.damage);
             }
@@ -738,3 +1339,118 @@ fn update_killcount(mut ev_kill: EventReader<KillEvent>, mut q_towers: Query<&mu
 // self.bullet.spawn(wait - 2)
 // create all the bullets. take some time before being active
 // I don't like the consequences of this. they will be visible just sitting there
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn degrees(dir: Vec2) -> f32 {
+        dir.y.atan2(dir.x).to_degrees()
+    }
+
+    #[test]
+    fn pellets_fan_out_evenly_around_the_aim() {
+        let spread = SpreadInfo::new(5, 40.0, 0.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let dirs = spread.pellet_dirs(Vec2::X, &mut rng);
+
+        assert_eq!(dirs.len(), 5);
+        for (dir, expected) in dirs.iter().zip([-20.0, -10.0, 0.0, 10.0, 20.0]) {
+            assert!((degrees(*dir) - expected).abs() < 1e-3, "{:?}", dir);
+            assert!((dir.length() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn single_pellet_goes_straight() {
+        let spread = SpreadInfo::new(1, 40.0, 0.0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let dirs = spread.pellet_dirs(Vec2::new(0.0, 2.0), &mut rng);
+
+        assert_eq!(dirs.len(), 1);
+        assert!(dirs[0].abs_diff_eq(Vec2::new(0.0, 2.0), 1e-5));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_range() {
+        let spread = SpreadInfo::new(3, 20.0, 5.0);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..50 {
+            let dirs = spread.pellet_dirs(Vec2::X, &mut rng);
+            for (dir, centre) in dirs.iter().zip([-10.0, 0.0, 10.0]) {
+                assert!((degrees(*dir) - centre).abs() <= 5.0 + 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_spread_repeats() {
+        let spread = SpreadInfo::new(4, 30.0, 3.0);
+        let first = spread.pellet_dirs(Vec2::X, &mut StdRng::seed_from_u64(3));
+        let second = spread.pellet_dirs(Vec2::X, &mut StdRng::seed_from_u64(3));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn zero_pellets_still_fires_one() {
+        let spread = SpreadInfo::new(0, 30.0, 0.0);
+        let dirs = spread.pellet_dirs(Vec2::X, &mut StdRng::seed_from_u64(0));
+        assert_eq!(dirs.len(), 1);
+    }
+
+    #[test]
+    fn falloff_ending_before_it_starts_drops_straight_away() {
+        let falloff = DamageFalloff::new(50.0, 10.0, 1);
+        assert_eq!(falloff.apply(5, 40.0), 5);
+        assert_eq!(falloff.apply(5, 60.0), 1);
+    }
+
+    fn assert_near(time: Option<f32>, expected: f32) {
+        match time {
+            Some(time) => assert!((time - expected).abs() < 1e-3, "{} != {}", time, expected),
+            None => panic!("expected {}, got None", expected),
+        }
+    }
+
+    #[test]
+    fn intercept_still_target() {
+        assert_near(
+            intercept_time(Vec2::new(100.0, 0.0), Vec2::ZERO, 10.0, 0.0),
+            10.0,
+        );
+    }
+
+    #[test]
+    fn intercept_crossing_target() {
+        // meets it at (30, 40), 50 away
+        assert_near(
+            intercept_time(Vec2::new(30.0, 0.0), Vec2::new(0.0, 40.0), 50.0, 0.0),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn intercept_from_a_head_start() {
+        // the bullet starts 20 along, so it only has 80 left to cover
+        assert_near(
+            intercept_time(Vec2::new(100.0, 0.0), Vec2::ZERO, 10.0, 20.0),
+            8.0,
+        );
+    }
+
+    #[test]
+    fn intercept_target_as_fast_as_the_bullet() {
+        // coming straight at us closes at twice the speed
+        assert_near(
+            intercept_time(Vec2::new(100.0, 0.0), Vec2::new(-10.0, 0.0), 10.0, 0.0),
+            5.0,
+        );
+        // going sideways it can never be caught
+        assert!(intercept_time(Vec2::new(100.0, 0.0), Vec2::new(0.0, 10.0), 10.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn cant_catch_a_faster_target_running_away() {
+        assert!(intercept_time(Vec2::new(100.0, 0.0), Vec2::new(20.0, 0.0), 10.0, 0.0).is_none());
+    }
+}
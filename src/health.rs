@@ -1,12 +1,43 @@
 #![allow(dead_code)]
 
-use bevy::prelude::*;
+use std::time::Duration;
+
+use bevy::{prelude::*, sprite::Anchor};
+
+use crate::{level::LevelEntity, loading::FontAssets};
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DamageNumberPool { free: Vec::new() })
+            .add_system(tick_health)
+            .add_system(spawn_health_bars)
+            .add_system(update_health_bars.after(spawn_health_bars))
+            .add_system(spawn_damage_numbers)
+            .add_system(tick_damage_numbers);
+    }
+}
 
 #[derive(Component)]
 pub struct Health {
     max_health: u32,
     current_health: u32,
     just_died: bool,
+    regen_per_second: f32,
+    // partial health regenerated so far
+    regen_progress: f32,
+    shield: Option<Shield>,
+}
+
+/// Absorbs damage before health does.
+/// Starts recharging once it hasn't been hit for `recharge_delay`.
+struct Shield {
+    max: u32,
+    current: u32,
+    recharge_delay: Timer,
+    recharge_per_second: f32,
+    recharge_progress: f32,
 }
 
 impl Health {
@@ -16,6 +47,44 @@ impl Health {
             max_health,
             current_health: max_health,
             just_died: false,
+            regen_per_second: 0.0,
+            regen_progress: 0.0,
+            shield: None,
+        }
+    }
+
+    pub fn with_regen(mut self, per_second: f32) -> Self {
+        self.regen_per_second = per_second;
+        self
+    }
+
+    pub fn with_shield(mut self, max: u32, recharge_delay: f32, recharge_per_second: f32) -> Self {
+        self.shield = Some(Shield {
+            max,
+            current: max,
+            recharge_delay: Timer::from_seconds(recharge_delay, false),
+            recharge_per_second,
+            recharge_progress: 0.0,
+        });
+        self
+    }
+
+    /// Tops up the shield, up to its max.
+    /// Adds a shield that doesn't recharge if there wasn't one.
+    pub fn add_shield(&mut self, amount: u32) {
+        match &mut self.shield {
+            Some(shield) => {
+                shield.current = (shield.current + amount).min(shield.max);
+            }
+            None => {
+                self.shield = Some(Shield {
+                    max: amount,
+                    current: amount,
+                    recharge_delay: Timer::from_seconds(0.0, false),
+                    recharge_per_second: 0.0,
+                    recharge_progress: 0.0,
+                });
+            }
         }
     }
 
@@ -25,6 +94,20 @@ impl Health {
             // already dead
             return;
         }
+
+        let mut damage = damage;
+        if let Some(shield) = &mut self.shield {
+            let absorbed = damage.min(shield.current);
+            shield.current -= absorbed;
+            damage -= absorbed;
+            // wait again before recharging
+            shield.recharge_delay.reset();
+            shield.recharge_progress = 0.0;
+        }
+        if damage == 0 {
+            return;
+        }
+
         if damage >= self.current_health {
             self.current_health = 0;
             // just died
@@ -35,6 +118,78 @@ impl Health {
         }
     }
 
+    /// Restores health up to max. Can't bring back the dead.
+    pub fn heal(&mut self, amount: u32) {
+        if self.is_dead() {
+            return;
+        }
+        self.current_health = (self.current_health + amount).min(self.max_health);
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current_health
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max_health
+    }
+
+    /// Current health from 0.0 to 1.0
+    pub fn fraction(&self) -> f32 {
+        if self.max_health == 0 {
+            return 0.0;
+        }
+        self.current_health as f32 / self.max_health as f32
+    }
+
+    pub fn shield(&self) -> u32 {
+        self.shield.as_ref().map_or(0, |shield| shield.current)
+    }
+
+    pub fn max_shield(&self) -> u32 {
+        self.shield.as_ref().map_or(0, |shield| shield.max)
+    }
+
+    /// Whether there is any regen or shield recharge to do.
+    /// Check before ticking so change detection only fires when it needs to.
+    fn needs_tick(&self) -> bool {
+        if self.is_dead() {
+            return false;
+        }
+        let regen = self.regen_per_second > 0.0 && self.current_health < self.max_health;
+        let recharge = matches!(&self.shield, Some(shield)
+            if shield.current < shield.max && shield.recharge_per_second > 0.0);
+        regen || recharge
+    }
+
+    fn tick(&mut self, delta: Duration) {
+        if self.is_dead() {
+            return;
+        }
+
+        if self.current_health < self.max_health {
+            self.regen_progress += self.regen_per_second * delta.as_secs_f32();
+            let whole = self.regen_progress.floor();
+            if whole >= 1.0 {
+                self.regen_progress -= whole;
+                self.heal(whole as u32);
+            }
+        } else {
+            self.regen_progress = 0.0;
+        }
+
+        if let Some(shield) = &mut self.shield {
+            if shield.current < shield.max && shield.recharge_delay.tick(delta).finished() {
+                shield.recharge_progress += shield.recharge_per_second * delta.as_secs_f32();
+                let whole = shield.recharge_progress.floor();
+                if whole >= 1.0 {
+                    shield.recharge_progress -= whole;
+                    shield.current = (shield.current + whole as u32).min(shield.max);
+                }
+            }
+        }
+    }
+
     pub fn is_dead(&self) -> bool {
         self.current_health == 0
     }
@@ -45,3 +200,219 @@ impl Health {
         self.just_died
     }
 }
+
+fn tick_health(mut q_health: Query<&mut Health>, time: Res<Time>) {
+    for mut health in &mut q_health {
+        if health.needs_tick() {
+            health.tick(time.delta());
+        }
+    }
+}
+
+/// Shows a bar above the entity once it has taken damage.
+#[derive(Component)]
+pub struct HealthBar {
+    width: f32,
+    // how far above the center of the entity
+    height_offset: f32,
+    fill: Option<Entity>,
+    background: Option<Entity>,
+}
+
+impl HealthBar {
+    pub fn new(width: f32, height_offset: f32) -> Self {
+        HealthBar {
+            width,
+            height_offset,
+            fill: None,
+            background: None,
+        }
+    }
+
+    /// Bar for a wall or tower filling a tile `tile_size` across.
+    pub fn for_tile(tile_size: f32) -> Self {
+        HealthBar::new(tile_size * 0.875, tile_size * 0.5625)
+    }
+
+    /// Removes the bar's sprites. For when the component is removed but the entity lives on.
+    pub fn despawn(&self, commands: &mut Commands) {
+        for part in [self.fill, self.background].into_iter().flatten() {
+            commands.entity(part).despawn_recursive();
+        }
+    }
+}
+
+const HEALTH_BAR_HEIGHT: f32 = 4.0;
+
+// green at full health to red when empty
+fn health_bar_colour(fraction: f32) -> Color {
+    Color::rgb(1.0 - fraction, fraction, 0.0)
+}
+
+fn spawn_health_bars(
+    mut commands: Commands,
+    mut q_health: Query<(Entity, &Health, &mut HealthBar), Changed<Health>>,
+) {
+    for (entity, health, mut bar) in &mut q_health {
+        if bar.fill.is_some() || health.current() == health.max() {
+            continue;
+        }
+
+        let background = commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::BLACK,
+                    custom_size: Some(Vec2::new(bar.width + 2.0, HEALTH_BAR_HEIGHT + 2.0)),
+                    ..default()
+                },
+                transform: Transform::from_translation(Vec3::new(0.0, bar.height_offset, 0.2)),
+                ..default()
+            })
+            .id();
+        // shrinks towards the left edge
+        let fraction = health.fraction();
+        let fill = commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: health_bar_colour(fraction),
+                    custom_size: Some(Vec2::new(bar.width, HEALTH_BAR_HEIGHT)),
+                    anchor: Anchor::CenterLeft,
+                    ..default()
+                },
+                transform: Transform {
+                    translation: Vec3::new(-0.5 * bar.width, bar.height_offset, 0.3),
+                    scale: Vec3::new(fraction, 1.0, 1.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .id();
+        commands.entity(entity).push_children(&[background, fill]);
+        bar.fill = Some(fill);
+        bar.background = Some(background);
+    }
+}
+
+fn update_health_bars(
+    q_health: Query<(&Health, &HealthBar), Changed<Health>>,
+    mut q_fill: Query<(&mut Transform, &mut Sprite)>,
+) {
+    for (health, bar) in &q_health {
+        if let Some(fill) = bar.fill {
+            if let Ok((mut trans, mut sprite)) = q_fill.get_mut(fill) {
+                let fraction = health.fraction();
+                trans.scale.x = fraction;
+                sprite.color = health_bar_colour(fraction);
+            }
+        }
+    }
+}
+
+/// Pops up a number whenever the entity loses health.
+#[derive(Component, Default)]
+pub struct DamageNumbers {
+    last_health: Option<u32>,
+}
+
+// damage number text entities that aren't showing right now
+struct DamageNumberPool {
+    free: Vec<Entity>,
+}
+
+#[derive(Component)]
+struct DamageNumber {
+    lifetime: Timer,
+}
+
+fn spawn_damage_numbers(
+    mut commands: Commands,
+    mut q_health: Query<(&GlobalTransform, &Health, &mut DamageNumbers), Changed<Health>>,
+    mut q_numbers: Query<
+        (
+            &mut Text,
+            &mut Transform,
+            &mut Visibility,
+            &mut DamageNumber,
+        ),
+        Without<DamageNumbers>,
+    >,
+    mut pool: ResMut<DamageNumberPool>,
+    fonts: Option<Res<FontAssets>>,
+) {
+    let fonts = match fonts {
+        Some(fonts) => fonts,
+        None => return,
+    };
+
+    for (global_trans, health, mut numbers) in &mut q_health {
+        let last_health = numbers.last_health.unwrap_or_else(|| health.max());
+        numbers.last_health = Some(health.current());
+        if health.current() >= last_health {
+            continue;
+        }
+        let damage = last_health - health.current();
+        let pos = global_trans.translation() + Vec3::new(0.0, 10.0, 0.0);
+        let pos = Vec3::new(pos.x, pos.y, 0.6);
+
+        // reuse one from the pool if there is one
+        if let Some(number_ent) = pool.free.pop() {
+            if let Ok((mut text, mut trans, mut visibility, mut number)) =
+                q_numbers.get_mut(number_ent)
+            {
+                text.sections[0].value = damage.to_string();
+                text.sections[0].style.color = Color::WHITE;
+                trans.translation = pos;
+                visibility.is_visible = true;
+                number.lifetime.reset();
+                continue;
+            }
+        }
+
+        commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::from_section(
+                    damage.to_string(),
+                    TextStyle {
+                        font: fonts.fira_sans.clone(),
+                        font_size: 16.0,
+                        color: Color::WHITE,
+                    },
+                )
+                .with_alignment(TextAlignment::CENTER),
+                transform: Transform::from_translation(pos),
+                ..default()
+            })
+            .insert(DamageNumber {
+                lifetime: Timer::from_seconds(0.8, false),
+            })
+            .insert(LevelEntity);
+    }
+}
+
+fn tick_damage_numbers(
+    mut q_numbers: Query<(
+        Entity,
+        &mut Text,
+        &mut Transform,
+        &mut Visibility,
+        &mut DamageNumber,
+    )>,
+    mut pool: ResMut<DamageNumberPool>,
+    time: Res<Time>,
+) {
+    for (entity, mut text, mut trans, mut visibility, mut number) in &mut q_numbers {
+        if !visibility.is_visible {
+            continue;
+        }
+        if number.lifetime.tick(time.delta()).just_finished() {
+            // back in the pool
+            visibility.is_visible = false;
+            pool.free.push(entity);
+            continue;
+        }
+        // float up and fade out
+        trans.translation += Vec3::new(0.0, 40.0 * time.delta_seconds(), 0.0);
+        let alpha = number.lifetime.percent_left();
+        text.sections[0].style.color.set_a(alpha);
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    enemy::Enemy,
+    gold::{Generator, Treasury},
+    grid::{RemoveStructureEvent, TerritoryGrabber, Tile},
+    resource_container::{Resource, ResourceKind},
+    swap::SwapEvent,
+    tower::{PlaceTowerEvent, TowerType},
+    wall::PlaceWallEvent,
+};
+
+pub struct HistoryPlugin;
+
+impl Plugin for HistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(History::default())
+            .add_event::<RecordActionEvent>()
+            .add_system(record_actions.before(undo_redo))
+            .add_system(undo_redo)
+            .add_system(end_build_phase.before(record_actions));
+    }
+}
+
+/// Something the player did to the grid that can be undone.
+/// Holds enough to put the grid, tiles and treasury back the way they were.
+#[derive(Clone, Debug)]
+pub enum GridAction {
+    Swap {
+        // dragged tile first
+        tiles: [Entity; 2],
+        before: [Resource; 2],
+        after: [Resource; 2],
+        cost: Resource,
+    },
+    Wall {
+        tile: Entity,
+    },
+    Build {
+        tile: Entity,
+        tower_type: TowerType,
+        cost: Resource,
+        // set once the tower's been sold. What the old tower claimed can't be checked any more
+        permanent: bool,
+    },
+    Sell {
+        tile: Entity,
+        tower_type: TowerType,
+        level: u32,
+        refund: Resource,
+    },
+}
+
+impl GridAction {
+    /// Whether the action has had effects since that undoing it can't take back.
+    /// A built tower can't be undone once it's claimed territory or made resources,
+    /// otherwise building then undoing would claim land for free.
+    fn has_lasting_effects(
+        &self,
+        q_claims: &Query<(Option<&TerritoryGrabber>, Option<&Generator>)>,
+    ) -> bool {
+        match self {
+            GridAction::Build {
+                permanent: true, ..
+            } => true,
+            GridAction::Build { tile, .. } => match q_claims.get(*tile) {
+                Ok((grabber, generator)) => {
+                    matches!(grabber, Some(grabber) if grabber.has_grabbed())
+                        || matches!(generator, Some(generator) if generator.has_generated())
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// (spent, earned) by doing the action.
+    fn treasury_change(&self) -> (Resource, Resource) {
+        match self {
+            GridAction::Swap { cost, .. } | GridAction::Build { cost, .. } => {
+                (*cost, Resource::default())
+            }
+            GridAction::Wall { .. } => (Resource::default(), Resource::default()),
+            GridAction::Sell { refund, .. } => (Resource::default(), *refund),
+        }
+    }
+}
+
+/// Sent after an action has been done by the player, to add it to the [`History`].
+pub struct RecordActionEvent(pub GridAction);
+
+/// Actions that can be undone and redone.
+/// Only covers the current build phase. Cleared once enemies show up.
+pub struct History {
+    undo: VecDeque<GridAction>,
+    redo: Vec<GridAction>,
+    // most actions kept. The oldest are forgotten first
+    pub depth: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth: 50,
+        }
+    }
+}
+
+impl History {
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+fn record_actions(mut ev_record: EventReader<RecordActionEvent>, mut history: ResMut<History>) {
+    for ev in ev_record.iter() {
+        if history.depth == 0 {
+            continue;
+        }
+        // undoing the sell brings back a fresh tower, which would let the build be undone too
+        if let GridAction::Sell { tile: sold, .. } = ev.0 {
+            for action in history.undo.iter_mut() {
+                if let GridAction::Build {
+                    tile, permanent, ..
+                } = action
+                {
+                    if *tile == sold {
+                        *permanent = true;
+                    }
+                }
+            }
+        }
+        history.undo.push_back(ev.0.clone());
+        // a new action means the undone ones can't come back
+        history.redo.clear();
+        while history.undo.len() > history.depth {
+            history.undo.pop_front();
+        }
+    }
+}
+
+// there's no wave timer yet, so building is whenever no enemies are about
+fn end_build_phase(q_new_enemies: Query<(), Added<Enemy>>, mut history: ResMut<History>) {
+    if q_new_enemies.is_empty() {
+        return;
+    }
+    // read first so it's only marked as changed when there's something to clear
+    if !history.undo.is_empty() || !history.redo.is_empty() {
+        history.clear();
+    }
+}
+
+/// Moves the tiles' `current` stock by what changed going from `from` to `to`,
+/// keeping anything made or used since. None if a tile no longer has what needs to move.
+fn shift_stock(
+    current: [Resource; 2],
+    from: &[Resource; 2],
+    to: &[Resource; 2],
+) -> Option<[Resource; 2]> {
+    let mut shifted = [Resource::default(); 2];
+    for i in 0..2 {
+        for kind in ResourceKind::ALL {
+            let amount = (current[i].get(kind) + to[i].get(kind)).checked_sub(from[i].get(kind))?;
+            shifted[i].add(kind, amount);
+        }
+    }
+    Some(shifted)
+}
+
+fn put_back(history: &mut History, action: GridAction, undo: bool) {
+    if undo {
+        history.undo.push_back(action);
+    } else {
+        history.redo.push(action);
+    }
+}
+
+fn undo_redo(
+    keyboard: Res<Input<KeyCode>>,
+    mut history: ResMut<History>,
+    mut treasury: ResMut<Treasury>,
+    q_enemies: Query<(), With<Enemy>>,
+    q_tiles: Query<&Tile>,
+    q_claims: Query<(Option<&TerritoryGrabber>, Option<&Generator>)>,
+    mut q_resources: Query<&mut Resource, With<Tile>>,
+    mut ev_swap: EventWriter<SwapEvent>,
+    mut ev_remove: EventWriter<RemoveStructureEvent>,
+    mut ev_wall: EventWriter<PlaceWallEvent>,
+    mut ev_tower: EventWriter<PlaceTowerEvent>,
+) {
+    let undo = keyboard.just_pressed(KeyCode::Z);
+    if !undo && !keyboard.just_pressed(KeyCode::Y) {
+        return;
+    }
+    if !q_enemies.is_empty() {
+        println!("Can only undo while building");
+        return;
+    }
+    let action = if undo {
+        history.undo.pop_back()
+    } else {
+        history.redo.pop()
+    };
+    let action = match action {
+        Some(action) => action,
+        None => return,
+    };
+
+    if undo && action.has_lasting_effects(&q_claims) {
+        println!("Can't undo that, the tower has already claimed territory or made resources");
+        put_back(&mut history, action, undo);
+        return;
+    }
+
+    // only move back what joining the stacks moved
+    let restock = match &action {
+        GridAction::Swap {
+            tiles,
+            before,
+            after,
+            ..
+        } => {
+            let (from, to) = if undo {
+                (after, before)
+            } else {
+                (before, after)
+            };
+            let stock = match (q_resources.get(tiles[0]), q_resources.get(tiles[1])) {
+                (Ok(a), Ok(b)) => shift_stock([*a, *b], from, to),
+                _ => None,
+            };
+            if stock.is_none() {
+                println!(
+                    "Can't {} that swap, its resources have been used",
+                    if undo { "undo" } else { "redo" }
+                );
+                put_back(&mut history, action, undo);
+                return;
+            }
+            stock
+        }
+        _ => None,
+    };
+
+    // undoing gives back what was spent and takes back what was earned
+    let (spent, earned) = action.treasury_change();
+    let (pay, get) = if undo {
+        (earned, spent)
+    } else {
+        (spent, earned)
+    };
+    if !treasury.stock.pay(&pay) {
+        println!(
+            "Can't afford to {}. Costs {}",
+            if undo { "undo" } else { "redo" },
+            pay
+        );
+        put_back(&mut history, action, undo);
+        return;
+    }
+    for (kind, amount) in get.iter() {
+        treasury.stock.add(kind, amount);
+    }
+
+    match &action {
+        GridAction::Swap { tiles, .. } => {
+            if let (Ok(from), Ok(to)) = (q_tiles.get(tiles[0]), q_tiles.get(tiles[1])) {
+                ev_swap.send(SwapEvent {
+                    from: from.coords,
+                    to: to.coords,
+                    from_ent: tiles[0],
+                    to_ent: tiles[1],
+                    replay: true,
+                });
+            }
+            for (&tile, res) in tiles.iter().zip(restock.into_iter().flatten()) {
+                if let Ok(mut current) = q_resources.get_mut(tile) {
+                    *current = res;
+                }
+            }
+        }
+        GridAction::Wall { tile } => {
+            if undo {
+                ev_remove.send(RemoveStructureEvent { tile: *tile });
+            } else {
+                ev_wall.send(PlaceWallEvent { tile: *tile });
+            }
+        }
+        GridAction::Build {
+            tile, tower_type, ..
+        } => {
+            if undo {
+                ev_remove.send(RemoveStructureEvent { tile: *tile });
+            } else {
+                ev_tower.send(PlaceTowerEvent {
+                    tile: *tile,
+                    tower_type: *tower_type,
+                    level: 0,
+                });
+            }
+        }
+        GridAction::Sell {
+            tile,
+            tower_type,
+            level,
+            ..
+        } => {
+            if undo {
+                ev_tower.send(PlaceTowerEvent {
+                    tile: *tile,
+                    tower_type: *tower_type,
+                    level: *level,
+                });
+            } else {
+                ev_remove.send(RemoveStructureEvent { tile: *tile });
+            }
+        }
+    }
+
+    if undo {
+        history.redo.push(action);
+    } else {
+        history.undo.push_back(action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ResourceKind::*;
+
+    #[test]
+    fn undoing_a_swap_keeps_what_was_made_since() {
+        // the swap joined the gold onto the dragged tile
+        let before = [Resource::of(Gold, 2), Resource::of(Gold, 3)];
+        let after = [Resource::of(Gold, 5), Resource::default()];
+        // a generator dropped more on both tiles since
+        let current = [Resource::of(Gold, 6), Resource::of(Stone, 1)];
+
+        let undone = shift_stock(current, &after, &before).unwrap();
+        assert_eq!(
+            undone,
+            [Resource::of(Gold, 3), Resource::of(Gold, 3).with(Stone, 1)]
+        );
+    }
+
+    #[test]
+    fn cant_undo_a_swap_once_the_stock_is_used() {
+        let before = [Resource::of(Gold, 2), Resource::of(Gold, 3)];
+        let after = [Resource::of(Gold, 5), Resource::default()];
+        // spent on ammo, so the 3 can't go back
+        let current = [Resource::of(Gold, 1), Resource::default()];
+
+        assert!(shift_stock(current, &after, &before).is_none());
+    }
+}
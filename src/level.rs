@@ -0,0 +1,879 @@
+//! Levels are plain text. A header of `key = value` lines, then `map:` and one line per row
+//! of tiles, top row first. Lines starting with `//` before the map are comments.
+//!
+//! Header keys:
+//! - `name` the name shown in the menu. Required
+//! - `gold`, `stone`, `mana` what the treasury starts with. 0 if left out
+//! - `tile_size` in pixels. 32 if left out
+//! - `stock` resources a tile starts with, as `column row: amount kind, amount kind`.
+//!   Columns and rows count from 1 at the top left of the map, like the error messages.
+//!   Can be given once per tile, e.g. `stock = 18 9: 5 gold, 2 stone`
+//!
+//! Map tiles:
+//! - `.` grass
+//! - `1` to `9` grass with this walking cost instead of its default of 2
+//! - `=` road. Cheapest to walk over, can't be built on
+//! - `m` mud. Slow going, nothing can be built or grown on it
+//! - `t` forest. Grows twice the gold
+//! - `~` water and `^` rock. Can't be walked over or built on
+//! - `S` where enemies come in. At least one
+//! - `G` where enemies are heading. Exactly one
+//! - `#` a wall to start with
+//! - `f` friendly and `h` hostile territory to start with
+//!
+//! Your own levels go in a `levels` folder next to where the game is run from.
+//! They show up in the menu each time it's opened, after the built in ones.
+
+use std::{collections::VecDeque, fmt};
+
+use bevy::prelude::*;
+
+use crate::{
+    flow_field::{FlowFieldGoal, RecalculateFlowFieldEvent},
+    gold::Treasury,
+    grid::{spawn_tile, Coords, Grid, GridLayout, Terrain, Tile, TileColours, TileType},
+    history::History,
+    loading::FontAssets,
+    resource_container::{Resource, ResourceKind},
+    wall::add_wall,
+    GameState,
+};
+
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_exit(GameState::Loading).with_system(create_level_menu));
+        app.insert_resource(LevelSpawns::default())
+            .insert_resource(LevelFiles::find())
+            .add_event::<LoadLevelEvent>()
+            .add_system(level_menu_buttons.before(load_level))
+            .add_system(toggle_level_menu.before(fill_level_menu))
+            .add_system(fill_level_menu)
+            .add_system(load_level);
+    }
+}
+
+// (file, contents). Built in so they work on the web too
+const BUILT_IN_LEVELS: [(&str, &str); 3] = [
+    (
+        "open_field.txt",
+        include_str!("../assets/levels/open_field.txt"),
+    ),
+    (
+        "river_crossing.txt",
+        include_str!("../assets/levels/river_crossing.txt"),
+    ),
+    (
+        "badlands.txt",
+        include_str!("../assets/levels/badlands.txt"),
+    ),
+];
+
+// checked for .txt files each time the menu opens. Not on the web
+const USER_LEVEL_DIR: &str = "levels";
+
+/// A level file the menu can offer.
+pub struct LevelFile {
+    pub file: String,
+    // Err when the file couldn't be read
+    contents: Result<String, String>,
+}
+
+impl LevelFile {
+    fn parse(&self) -> Result<Level, LevelError> {
+        match &self.contents {
+            Ok(text) => parse_level(text),
+            Err(err) => Err(LevelError::new(err.clone())),
+        }
+    }
+}
+
+/// The built in levels, then any in [`USER_LEVEL_DIR`].
+pub struct LevelFiles(pub Vec<LevelFile>);
+
+impl LevelFiles {
+    fn find() -> Self {
+        let mut files: Vec<LevelFile> = BUILT_IN_LEVELS
+            .iter()
+            .map(|(file, text)| LevelFile {
+                file: file.to_string(),
+                contents: Ok(text.to_string()),
+            })
+            .collect();
+        files.extend(user_level_files());
+        LevelFiles(files)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn user_level_files() -> Vec<LevelFile> {
+    // no folder is fine, there just aren't any
+    let entries = match std::fs::read_dir(USER_LEVEL_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| matches!(path.extension(), Some(ext) if ext == "txt"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| LevelFile {
+            file: path.display().to_string(),
+            contents: std::fs::read_to_string(&path).map_err(|err| err.to_string()),
+        })
+        .collect()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn user_level_files() -> Vec<LevelFile> {
+    Vec::new()
+}
+
+/// Anything that belongs to the map being played: tiles, enemies, shots and effects.
+/// All of it is despawned when another level is loaded.
+#[derive(Component)]
+pub struct LevelEntity;
+
+/// Where enemies come in on the current level. Empty until a level is loaded.
+#[derive(Default)]
+pub struct LevelSpawns(pub Vec<Coords>);
+
+/// A level read from a file.
+pub struct Level {
+    pub name: String,
+    pub layout: GridLayout,
+    // indexed by GridLayout::index
+    tiles: Vec<LevelTile>,
+    pub spawns: Vec<Coords>,
+    pub goal: Coords,
+    // what the treasury starts with
+    pub start: Resource,
+}
+
+#[derive(Copy, Clone)]
+struct LevelTile {
+    terrain: Terrain,
+    // overrides what the terrain costs to walk over
+    base_cost: Option<u8>,
+    tile_type: TileType,
+    wall: bool,
+    resources: Resource,
+}
+
+impl Default for LevelTile {
+    fn default() -> Self {
+        LevelTile {
+            terrain: Terrain::Grass,
+            base_cost: None,
+            tile_type: TileType::Neutral,
+            wall: false,
+            resources: Resource::default(),
+        }
+    }
+}
+
+/// Why a level file couldn't be read.
+#[derive(Debug)]
+pub struct LevelError {
+    // line in the file, from 1
+    line: Option<usize>,
+    message: String,
+}
+
+impl LevelError {
+    fn new(message: impl Into<String>) -> Self {
+        LevelError {
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    fn at(line: usize, message: impl Into<String>) -> Self {
+        LevelError {
+            line: Some(line),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub fn parse_level(text: &str) -> Result<Level, LevelError> {
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+    // header
+    let mut name = None;
+    let mut start = Resource::default();
+    let mut tile_size = GridLayout::default().tile_size;
+    let mut seen_keys = Vec::new();
+    // (line, column, row, resources). Checked against the map once it's read
+    let mut stocks = Vec::new();
+    let mut map_line = None;
+    for (number, line) in lines.by_ref() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if line == "map:" {
+            map_line = Some(number);
+            break;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                return Err(LevelError::at(
+                    number,
+                    format!("expected `key = value` or `map:`, got `{}`", line),
+                ))
+            }
+        };
+        if key == "stock" {
+            let (column, row, resources) = parse_stock(number, value)?;
+            stocks.push((number, column, row, resources));
+            continue;
+        }
+        if seen_keys.contains(&key) {
+            return Err(LevelError::at(number, format!("`{}` is set twice", key)));
+        }
+        seen_keys.push(key);
+
+        match key {
+            "name" if value.is_empty() => {
+                return Err(LevelError::at(number, "`name` can't be empty"));
+            }
+            "name" => name = Some(value.to_string()),
+            "gold" | "stone" | "mana" => {
+                let kind = ResourceKind::ALL
+                    .into_iter()
+                    .find(|kind| kind.name() == key)
+                    .unwrap();
+                let amount = value.parse::<u32>().map_err(|_| {
+                    LevelError::at(
+                        number,
+                        format!("`{}` should be a whole number, got `{}`", key, value),
+                    )
+                })?;
+                start.add(kind, amount);
+            }
+            "tile_size" => {
+                tile_size = match value.parse::<f32>() {
+                    Ok(size) if size > 0.0 => size,
+                    _ => {
+                        return Err(LevelError::at(
+                            number,
+                            format!("`tile_size` should be above 0, got `{}`", value),
+                        ))
+                    }
+                };
+            }
+            _ => {
+                return Err(LevelError::at(
+                    number,
+                    format!(
+                        "unknown key `{}`. Expected name, gold, stone, mana, tile_size or stock",
+                        key
+                    ),
+                ))
+            }
+        }
+    }
+    let map_line = map_line.ok_or_else(|| LevelError::new("missing the `map:` line"))?;
+    let name = name.ok_or_else(|| LevelError::new("missing `name`"))?;
+
+    // map
+    let mut rows: Vec<(usize, &str)> = lines
+        .map(|(number, row)| (number, row.trim_end()))
+        .collect();
+    while matches!(rows.last(), Some((_, row)) if row.is_empty()) {
+        rows.pop();
+    }
+    let width = match rows.first() {
+        Some((_, row)) => row.chars().count(),
+        None => return Err(LevelError::at(map_line, "the map has no rows")),
+    };
+    for &(number, row) in &rows {
+        let row_width = row.chars().count();
+        if row_width != width {
+            return Err(LevelError::at(
+                number,
+                format!(
+                    "row is {} tiles wide but the first row is {}",
+                    row_width, width
+                ),
+            ));
+        }
+    }
+    let layout = GridLayout {
+        width,
+        height: rows.len(),
+        tile_size,
+    };
+
+    let mut tiles = vec![LevelTile::default(); layout.tile_count()];
+    // (line, column, coords)
+    let mut spawns = Vec::new();
+    let mut goals = Vec::new();
+    for (row_index, &(number, row)) in rows.iter().enumerate() {
+        // top row first
+        let y = layout.height - 1 - row_index;
+        for (x, c) in row.chars().enumerate() {
+            let coords = Coords { x, y };
+            let tile = &mut tiles[layout.index(x, y)];
+            match c {
+                '.' => {}
+                '1'..='9' => tile.base_cost = Some(c.to_digit(10).unwrap() as u8),
+                '=' => tile.terrain = Terrain::Road,
+                'm' => tile.terrain = Terrain::Mud,
+                't' => tile.terrain = Terrain::Forest,
+                '~' => tile.terrain = Terrain::Water,
+                '^' => tile.terrain = Terrain::Rock,
+                'S' => spawns.push((number, x + 1, coords)),
+                'G' => goals.push((number, x + 1, coords)),
+                '#' => tile.wall = true,
+                'f' => tile.tile_type = TileType::Friendly,
+                'h' => tile.tile_type = TileType::Hostile,
+                _ => {
+                    return Err(LevelError::at(
+                        number,
+                        format!("unknown tile `{}` in column {}", c, x + 1),
+                    ))
+                }
+            }
+        }
+    }
+
+    let mut stocked = Vec::new();
+    for (number, column, row, resources) in stocks {
+        if column > layout.width || row > layout.height {
+            return Err(LevelError::at(
+                number,
+                format!(
+                    "stock for column {} row {} is off the {}x{} map",
+                    column, row, layout.width, layout.height
+                ),
+            ));
+        }
+        // rows count down from the top
+        let (x, y) = (column - 1, layout.height - row);
+        if stocked.contains(&(x, y)) {
+            return Err(LevelError::at(
+                number,
+                format!("column {} row {} is stocked twice", column, row),
+            ));
+        }
+        stocked.push((x, y));
+        tiles[layout.index(x, y)].resources = resources;
+    }
+
+    let goal = match goals.as_slice() {
+        [] => return Err(LevelError::new("no goal. Mark one tile with `G`")),
+        [(_, _, goal)] => *goal,
+        [_, (number, column, _), ..] => {
+            return Err(LevelError::at(
+                *number,
+                format!("second goal in column {}. Only one is supported", column),
+            ))
+        }
+    };
+    if spawns.is_empty() {
+        return Err(LevelError::new(
+            "no spawn points. Mark at least one tile with `S`",
+        ));
+    }
+    // walls can be broken through so only terrain blocks the way
+    let reachable = reachable_from(&layout, &tiles, goal);
+    for &(number, column, coords) in &spawns {
+        if !reachable[layout.index(coords.x, coords.y)] {
+            return Err(LevelError::at(
+                number,
+                format!(
+                    "spawn in column {} has no way to the goal past the water and rock",
+                    column
+                ),
+            ));
+        }
+    }
+
+    Ok(Level {
+        name,
+        layout,
+        tiles,
+        spawns: spawns.into_iter().map(|(_, _, coords)| coords).collect(),
+        goal,
+        start,
+    })
+}
+
+// which tiles can walk to `from`. Indexed by GridLayout::index
+fn reachable_from(layout: &GridLayout, tiles: &[LevelTile], from: Coords) -> Vec<bool> {
+    let mut reached = vec![false; tiles.len()];
+    let mut queue = VecDeque::new();
+    reached[layout.index(from.x, from.y)] = true;
+    queue.push_back(from);
+    while let Some(Coords { x, y }) = queue.pop_front() {
+        let neighbours = [
+            (x.checked_sub(1), Some(y)),
+            (Some(x + 1), Some(y)),
+            (Some(x), y.checked_sub(1)),
+            (Some(x), Some(y + 1)),
+        ];
+        for (nx, ny) in neighbours {
+            let (nx, ny) = match (nx, ny) {
+                (Some(nx), Some(ny)) if layout.contains(nx, ny) => (nx, ny),
+                _ => continue,
+            };
+            let i = layout.index(nx, ny);
+            if !reached[i] && tiles[i].terrain.is_passable() {
+                reached[i] = true;
+                queue.push_back(Coords { x: nx, y: ny });
+            }
+        }
+    }
+    reached
+}
+
+/// Reads a `stock` value: `column row: amount kind, amount kind`.
+/// Returns the 1 based column and row and what's on the tile.
+fn parse_stock(number: usize, value: &str) -> Result<(usize, usize, Resource), LevelError> {
+    let expected = || {
+        LevelError::at(
+            number,
+            format!(
+                "expected `column row: amount kind, ...` for `stock`, got `{}`",
+                value
+            ),
+        )
+    };
+    let (position, contents) = value.split_once(':').ok_or_else(expected)?;
+    let mut position = position.split_whitespace().map(|n| n.parse::<usize>());
+    let (column, row) = match (position.next(), position.next(), position.next()) {
+        (Some(Ok(column)), Some(Ok(row)), None) if column > 0 && row > 0 => (column, row),
+        _ => return Err(expected()),
+    };
+
+    let mut resources = Resource::default();
+    for entry in contents.split(',') {
+        let (amount, kind) = entry.trim().split_once(' ').ok_or_else(expected)?;
+        let amount = amount.parse::<u32>().map_err(|_| expected())?;
+        let kind = ResourceKind::ALL
+            .into_iter()
+            .find(|k| k.name() == kind.trim())
+            .ok_or_else(|| {
+                LevelError::at(
+                    number,
+                    format!(
+                        "unknown resource `{}`. Expected gold, stone or mana",
+                        kind.trim()
+                    ),
+                )
+            })?;
+        match resources.get(kind).checked_add(amount) {
+            Some(total) if total <= kind.cap() => resources.add(kind, amount),
+            total => {
+                return Err(LevelError::at(
+                    number,
+                    format!(
+                        "a tile can hold at most {} {}, got {}",
+                        kind.cap(),
+                        kind.name(),
+                        total.map_or_else(|| format!("more than {}", u32::MAX), |t| t.to_string())
+                    ),
+                ))
+            }
+        }
+    }
+    Ok((column, row, resources))
+}
+
+/// Replaces the current map with one of the [`LevelFiles`].
+pub struct LoadLevelEvent(pub usize);
+
+fn load_level(
+    mut commands: Commands,
+    mut ev_load: EventReader<LoadLevelEvent>,
+    files: Res<LevelFiles>,
+    mut grid: ResMut<Grid>,
+    mut layout: ResMut<GridLayout>,
+    tile_colours: Res<TileColours>,
+    q_old: Query<Entity, With<LevelEntity>>,
+    mut goal: ResMut<FlowFieldGoal>,
+    mut spawns: ResMut<LevelSpawns>,
+    mut treasury: ResMut<Treasury>,
+    mut history: ResMut<History>,
+    mut ev_recalculate: EventWriter<RecalculateFlowFieldEvent>,
+) {
+    // only the last one matters
+    let index = match ev_load.iter().last() {
+        Some(ev) => ev.0,
+        None => return,
+    };
+    // the menu only has buttons for levels that parse
+    let level = match files.0.get(index).map(LevelFile::parse) {
+        Some(Ok(level)) => level,
+        _ => return,
+    };
+
+    for entity in &q_old {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    *layout = level.layout;
+    *grid = Grid::new(level.layout);
+    for x in 0..level.layout.width {
+        for y in 0..level.layout.height {
+            let info = level.tiles[level.layout.index(x, y)];
+            let mut tile = Tile::new(x, y).with_terrain(info.terrain);
+            tile.tile_type = info.tile_type;
+            if let Some(cost) = info.base_cost {
+                tile.base_cost = cost;
+                tile.cost = cost;
+            }
+            let tile_ent = spawn_tile(&mut commands, &layout, tile, &tile_colours);
+            if !info.resources.is_empty() {
+                commands.entity(tile_ent).insert(info.resources);
+            }
+            if info.wall {
+                add_wall(&mut commands, tile_ent, &mut tile, layout.tile_size);
+                // with the cost the wall gives it
+                commands.entity(tile_ent).insert(tile);
+            }
+            grid.tiles.push(tile_ent);
+        }
+    }
+
+    goal.0 = Some(level.goal);
+    spawns.0 = level.spawns;
+    treasury.stock = level.start;
+    // the old tiles are gone
+    history.clear();
+    ev_recalculate.send(RecalculateFlowFieldEvent);
+    println!("Loaded {}", level.name);
+}
+
+#[derive(Component)]
+struct LevelMenu;
+
+#[derive(Component)]
+struct LevelButton(usize);
+
+/// A button or error line in the menu. Replaced when the [`LevelFiles`] change.
+#[derive(Component)]
+struct LevelMenuEntry;
+
+fn create_level_menu(mut commands: Commands, fonts: Res<FontAssets>) {
+    let text_style = TextStyle {
+        font: fonts.fira_sans.clone(),
+        font_size: 24.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                // middle of the screen
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Percent(30.0),
+                    top: Val::Percent(25.0),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(40.0), Val::Auto),
+                // top-down
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                padding: UiRect::all(Val::Px(8.0)),
+                ..default()
+            },
+            // #262b44
+            color: Color::rgb_u8(0x26, 0x2b, 0x44).into(),
+            ..default()
+        })
+        .insert(LevelMenu)
+        .with_children(|root| {
+            root.spawn_bundle(TextBundle::from_section(
+                "Choose a level (esc)",
+                text_style.clone(),
+            ));
+            root.spawn_bundle(TextBundle::from_section(
+                format!("Add your own to the `{}` folder", USER_LEVEL_DIR),
+                TextStyle {
+                    font_size: 16.0,
+                    ..text_style
+                },
+            ));
+        });
+}
+
+// a button for each level that parses, and why for the ones that don't
+fn fill_level_menu(
+    mut commands: Commands,
+    files: Res<LevelFiles>,
+    fonts: Res<FontAssets>,
+    q_menu: Query<Entity, With<LevelMenu>>,
+    q_new_menu: Query<(), Added<LevelMenu>>,
+    q_entries: Query<Entity, With<LevelMenuEntry>>,
+) {
+    if !files.is_changed() && q_new_menu.is_empty() {
+        return;
+    }
+    for entity in &q_entries {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let text_style = TextStyle {
+        font: fonts.fira_sans.clone(),
+        font_size: 24.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    for menu in &q_menu {
+        commands.entity(menu).with_children(|root| {
+            for (index, file) in files.0.iter().enumerate() {
+                let level = match file.parse() {
+                    Ok(level) => level,
+                    Err(err) => {
+                        root.spawn_bundle(
+                            TextBundle::from_section(
+                                format!("{}: {}", file.file, err),
+                                TextStyle {
+                                    font_size: 16.0,
+                                    color: Color::rgb(0.9, 0.4, 0.4),
+                                    ..text_style.clone()
+                                },
+                            )
+                            .with_style(Style {
+                                max_size: Size::new(Val::Percent(80.0), Val::Undefined),
+                                margin: UiRect::all(Val::Px(4.0)),
+                                ..default()
+                            }),
+                        )
+                        .insert(LevelMenuEntry);
+                        continue;
+                    }
+                };
+                root.spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(80.0), Val::Auto),
+                        justify_content: JustifyContent::Center,
+                        padding: UiRect::all(Val::Px(8.0)),
+                        margin: UiRect::all(Val::Px(4.0)),
+                        ..default()
+                    },
+                    color: NORMAL_BUTTON.into(),
+                    ..default()
+                })
+                .insert(LevelButton(index))
+                .insert(LevelMenuEntry)
+                .with_children(|button| {
+                    button.spawn_bundle(TextBundle::from_section(
+                        format!(
+                            "{}  {}x{}",
+                            level.name, level.layout.width, level.layout.height
+                        ),
+                        text_style.clone(),
+                    ));
+                });
+            }
+        });
+    }
+}
+
+const NORMAL_BUTTON: Color = Color::rgb(0.15, 0.15, 0.15);
+const HOVERED_BUTTON: Color = Color::rgb(0.25, 0.25, 0.25);
+
+fn level_menu_buttons(
+    mut q_buttons: Query<(&Interaction, &mut UiColor, &LevelButton), Changed<Interaction>>,
+    mut q_menu: Query<&mut Style, With<LevelMenu>>,
+    mut ev_load: EventWriter<LoadLevelEvent>,
+) {
+    for (interaction, mut colour, button) in &mut q_buttons {
+        match *interaction {
+            Interaction::Clicked => {
+                ev_load.send(LoadLevelEvent(button.0));
+                for mut style in &mut q_menu {
+                    style.display = Display::None;
+                }
+            }
+            Interaction::Hovered => *colour = HOVERED_BUTTON.into(),
+            Interaction::None => *colour = NORMAL_BUTTON.into(),
+        }
+    }
+}
+
+fn toggle_level_menu(
+    keyboard: Res<Input<KeyCode>>,
+    mut q_menu: Query<&mut Style, With<LevelMenu>>,
+    mut files: ResMut<LevelFiles>,
+) {
+    if !keyboard.just_pressed(KeyCode::Escape) {
+        return;
+    }
+    for mut style in &mut q_menu {
+        style.display = match style.display {
+            Display::None => {
+                // pick up levels added since
+                *files = LevelFiles::find();
+                Display::Flex
+            }
+            Display::Flex => Display::None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(header: &str, rows: &[&str]) -> String {
+        format!("{}\nmap:\n{}\n", header, rows.join("\n"))
+    }
+
+    fn error(text: &str) -> LevelError {
+        match parse_level(text) {
+            Ok(level) => panic!("expected an error, loaded {}", level.name),
+            Err(err) => err,
+        }
+    }
+
+    fn tile(level: &Level, x: usize, y: usize) -> LevelTile {
+        level.tiles[level.layout.index(x, y)]
+    }
+
+    #[test]
+    fn built_in_levels_load() {
+        for (file, text) in BUILT_IN_LEVELS {
+            if let Err(err) = parse_level(text) {
+                panic!("{}: {}", file, err);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_header() {
+        let text = level(
+            "// a comment\nname = Test\ngold = 3\nmana = 2\ntile_size = 24",
+            &["S.G"],
+        );
+        let level = parse_level(&text).unwrap();
+        assert_eq!(level.name, "Test");
+        assert_eq!(level.layout.tile_size, 24.0);
+        assert_eq!(
+            level.start,
+            Resource::of(ResourceKind::Gold, 3).with(ResourceKind::Mana, 2)
+        );
+    }
+
+    #[test]
+    fn top_row_comes_first() {
+        let text = level("name = Test", &["S..", "..G"]);
+        let level = parse_level(&text).unwrap();
+        assert_eq!((level.layout.width, level.layout.height), (3, 2));
+        assert_eq!(level.spawns.len(), 1);
+        assert_eq!((level.spawns[0].x, level.spawns[0].y), (0, 1));
+        assert_eq!((level.goal.x, level.goal.y), (2, 0));
+    }
+
+    #[test]
+    fn reads_tiles() {
+        let text = level("name = Test", &["S=mt~^", "#fh5.G"]);
+        let level = parse_level(&text).unwrap();
+        let top: Vec<Terrain> = (0..6).map(|x| tile(&level, x, 1).terrain).collect();
+        assert_eq!(
+            top,
+            [
+                Terrain::Grass,
+                Terrain::Road,
+                Terrain::Mud,
+                Terrain::Forest,
+                Terrain::Water,
+                Terrain::Rock
+            ]
+        );
+        assert!(tile(&level, 0, 0).wall);
+        assert_eq!(tile(&level, 1, 0).tile_type, TileType::Friendly);
+        assert_eq!(tile(&level, 2, 0).tile_type, TileType::Hostile);
+        assert_eq!(tile(&level, 3, 0).base_cost, Some(5));
+        assert_eq!(tile(&level, 4, 0).base_cost, None);
+    }
+
+    #[test]
+    fn reads_stock() {
+        let text = level(
+            "name = Test\nstock = 1 1: 2 gold, 1 stone\nstock = 3 2: 4 mana",
+            &["S..", "..G"],
+        );
+        let level = parse_level(&text).unwrap();
+        assert_eq!(
+            tile(&level, 0, 1).resources,
+            Resource::of(ResourceKind::Gold, 2).with(ResourceKind::Stone, 1)
+        );
+        assert_eq!(
+            tile(&level, 2, 0).resources,
+            Resource::of(ResourceKind::Mana, 4)
+        );
+        assert!(tile(&level, 1, 1).resources.is_empty());
+    }
+
+    #[test]
+    fn header_errors() {
+        let map = ["S.G"];
+        assert!(error(&level("gold = 2", &map)).message.contains("name"));
+        assert_eq!(error(&level("name = A\nname = B", &map)).line, Some(2));
+        assert_eq!(error(&level("name = A\nsize = 3", &map)).line, Some(2));
+        assert_eq!(error(&level("name = A\ngold = lots", &map)).line, Some(2));
+        assert_eq!(error(&level("name = A\ntile_size = 0", &map)).line, Some(2));
+        assert_eq!(error(&level("name = A\njust words", &map)).line, Some(2));
+        assert!(error("name = A\n").message.contains("map:"));
+    }
+
+    #[test]
+    fn stock_errors() {
+        let map = ["S..", "..G"];
+        for stock in [
+            "stock = 4 1: 1 gold",
+            "stock = 1 3: 1 gold",
+            "stock = 0 1: 1 gold",
+            "stock = 1 1",
+            "stock = 1 1: gold",
+            "stock = 1 1: 1 wood",
+            "stock = 1 1: 100 gold",
+            "stock = 1 1: 1 gold, 4294967295 gold",
+        ] {
+            let err = error(&level(&format!("name = A\n{}", stock), &map));
+            assert_eq!(err.line, Some(2), "{}: {}", stock, err);
+        }
+        let twice = "name = A\nstock = 1 1: 1 gold\nstock = 1 1: 1 stone";
+        assert_eq!(error(&level(twice, &map)).line, Some(3));
+    }
+
+    #[test]
+    fn map_errors() {
+        // the rows start on line 3
+        assert_eq!(error(&level("name = A", &["S.G", ".."])).line, Some(4));
+        assert_eq!(error(&level("name = A", &["S.G", "..x"])).line, Some(4));
+        assert_eq!(error(&level("name = A", &["S.G", "..G"])).line, Some(4));
+        assert!(error(&level("name = A", &["S.."])).message.contains("goal"));
+        assert!(error(&level("name = A", &["..G"]))
+            .message
+            .contains("spawn"));
+        assert!(error("name = A\nmap:\n").message.contains("no rows"));
+    }
+
+    #[test]
+    fn spawns_need_a_way_to_the_goal() {
+        // walls can be broken through, water and rock can't
+        let text = level("name = A", &["S#G"]);
+        assert!(parse_level(&text).is_ok());
+
+        let text = level("name = A", &["~~~.", "S~.G", "^^^."]);
+        assert_eq!(error(&text).line, Some(4));
+    }
+}
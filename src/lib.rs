@@ -2,34 +2,55 @@
 
 use bevy::{prelude::*, render::camera::RenderTarget};
 
+mod boss;
+mod conveyor;
 mod enemy;
 mod flow_field;
 mod gold;
 mod grid;
 mod gun;
 mod health;
+mod history;
+mod level;
 mod loading;
 mod resource_container;
+mod selection;
+mod spatial;
 mod swap;
 mod tower;
+mod visuals;
 mod wall;
 
+// insert a different SwapAnimation before adding GamePlugin to change how swaps slide
+pub use swap::{Easing, SwapAnimation};
+
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
         app.add_state(GameState::Loading)
             .insert_resource(MouseWorldPos(Vec2::ONE * 10000.0))
+            .insert_resource(GameMode::Classic)
             .add_plugin(loading::LoadingPlugin)
             .add_plugin(flow_field::FlowFieldPlugin)
             .add_plugin(grid::GridPlugin)
             .add_plugin(enemy::EnemyPlugin)
+            .add_plugin(boss::BossPlugin)
             .add_plugin(wall::WallPlugin)
             .add_plugin(gun::GunPlugin)
+            .add_plugin(health::HealthPlugin)
             .add_plugin(tower::TowerPlugin)
             .add_plugin(gold::GoldPlugin)
             .add_plugin(resource_container::ResourcePlugin)
             .add_plugin(swap::SwapPlugin)
+            .add_plugin(conveyor::ConveyorPlugin)
+            .add_plugin(spatial::SpatialPlugin)
+            .add_plugin(visuals::VisualsPlugin)
+            .add_plugin(history::HistoryPlugin)
+            .add_plugin(selection::SelectionPlugin)
+            .add_plugin(level::LevelPlugin)
+            .add_system(toggle_game_mode)
+            .add_system(fit_camera_to_grid.before(update_mouse_position))
             .add_system(update_mouse_position);
     }
 }
@@ -43,8 +64,72 @@ enum GameState {
     // End,
 }
 
+/// Rules that change between ways of playing.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum GameMode {
+    // bounties go straight into the treasury
+    Classic,
+    // bounties drop where the enemy died and need collecting
+    Scavenger,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BountyPayout {
+    Treasury,
+    Drop,
+}
+
+impl GameMode {
+    pub fn bounty_payout(&self) -> BountyPayout {
+        match self {
+            GameMode::Classic => BountyPayout::Treasury,
+            GameMode::Scavenger => BountyPayout::Drop,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Classic => "Classic",
+            GameMode::Scavenger => "Scavenger",
+        }
+    }
+}
+
+// M switches modes. Bounties already paid out stay where they went
+fn toggle_game_mode(keyboard: Res<Input<KeyCode>>, mut mode: ResMut<GameMode>) {
+    if keyboard.just_pressed(KeyCode::M) {
+        *mode = match *mode {
+            GameMode::Classic => GameMode::Scavenger,
+            GameMode::Scavenger => GameMode::Classic,
+        };
+    }
+}
+
 pub struct MouseWorldPos(Vec2);
 
+// zooms out so bigger maps fit on screen. Small ones stay at one pixel per unit
+fn fit_camera_to_grid(
+    layout: Res<grid::GridLayout>,
+    windows: Res<Windows>,
+    mut q_camera: Query<&mut OrthographicProjection, With<Camera2d>>,
+) {
+    if !layout.is_changed() {
+        return;
+    }
+    let win = match windows.get_primary() {
+        Some(win) => win,
+        None => return,
+    };
+    // leave room for the store down the right side
+    let scale = (layout.width as f32 * layout.tile_size / (win.width() * 0.8))
+        .max(layout.height as f32 * layout.tile_size / win.height())
+        .max(1.0);
+    for mut projection in &mut q_camera {
+        projection.scale = scale;
+    }
+}
+
+#[allow(clippy::unnecessary_cast)]
 fn update_mouse_position(
     windows: Res<Windows>,
     q_camera: Query<(&Camera, &GlobalTransform)>,
@@ -5,17 +5,13 @@
 #![allow(clippy::too_many_arguments, clippy::type_complexity)]
 
 use bevy::prelude::*;
-use bevy_rapier2d::prelude::*;
+use bevy_prototype_lyon::prelude::ShapePlugin;
 use territory_td::GamePlugin;
 
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
-        .insert_resource(RapierConfiguration {
-            gravity: Vec2::ZERO,
-            ..default()
-        })
+        .add_plugin(ShapePlugin)
         .add_plugin(GamePlugin)
         .add_startup_system(setup)
         .run();
@@ -1,64 +1,408 @@
+use std::fmt;
+
 use bevy::{prelude::*, sprite::MaterialMesh2dBundle};
 
+use crate::{
+    grid::{Grid, Tile, TileType},
+    visuals::{VisualsCache, COIN_RADIUS},
+};
+
 pub struct ResourcePlugin;
 
 impl Plugin for ResourcePlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(check_max);
+        let rules = StorageRules::default();
+        app.insert_resource(DecayTimer(Timer::from_seconds(
+            rules.decay_interval.unwrap_or(1.0),
+            true,
+        )))
+        .insert_resource(rules)
+        .add_system(enforce_caps.before(check_max))
+        .add_system(decay_resources.before(enforce_caps))
+        .add_system(check_max);
+    }
+}
+
+/// How much a tile can hold and what happens to the rest.
+pub struct StorageRules {
+    // most of all kinds together on one tile
+    pub tile_capacity: u32,
+    // overflow goes to friendly neighbours before being lost
+    pub spill_to_neighbours: bool,
+    // seconds between losing one of each kind on tiles that aren't friendly.
+    // None to never decay
+    pub decay_interval: Option<f32>,
+}
+
+impl Default for StorageRules {
+    fn default() -> Self {
+        StorageRules {
+            tile_capacity: 16,
+            spill_to_neighbours: true,
+            decay_interval: Some(5.0),
+        }
+    }
+}
+
+struct DecayTimer(Timer);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    Gold,
+    Stone,
+    Mana,
+}
+
+impl ResourceKind {
+    pub const ALL: [ResourceKind; 3] =
+        [ResourceKind::Gold, ResourceKind::Stone, ResourceKind::Mana];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn colour(&self) -> Color {
+        match self {
+            ResourceKind::Gold => Color::GOLD,
+            ResourceKind::Stone => Color::SILVER,
+            ResourceKind::Mana => Color::VIOLET,
+        }
+    }
+
+    /// Most of this kind one tile can hold.
+    pub fn cap(&self) -> u32 {
+        match self {
+            ResourceKind::Gold => 10,
+            ResourceKind::Stone => 8,
+            ResourceKind::Mana => 5,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceKind::Gold => "gold",
+            ResourceKind::Stone => "stone",
+            ResourceKind::Mana => "mana",
+        }
+    }
+}
+
+/// A small inventory with a count of each [`ResourceKind`].
+/// Tiles hold one, and prices are written as one.
+#[derive(Component, Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Resource {
+    amounts: [u32; ResourceKind::ALL.len()],
+}
+
+impl Resource {
+    pub fn of(kind: ResourceKind, amount: u32) -> Self {
+        Resource::default().with(kind, amount)
+    }
+
+    pub fn with(mut self, kind: ResourceKind, amount: u32) -> Self {
+        self.add(kind, amount);
+        self
+    }
+
+    pub fn get(&self, kind: ResourceKind) -> u32 {
+        self.amounts[kind.index()]
+    }
+
+    pub fn add(&mut self, kind: ResourceKind, amount: u32) {
+        self.amounts[kind.index()] += amount;
+    }
+
+    /// Takes up to `amount` and returns how much was taken.
+    pub fn take(&mut self, kind: ResourceKind, amount: u32) -> u32 {
+        let taken = amount.min(self.get(kind));
+        self.amounts[kind.index()] -= taken;
+        taken
+    }
+
+    pub fn total(&self) -> u32 {
+        self.amounts.iter().sum()
+    }
+
+    /// How many more of `kind` fit under its cap and `tile_capacity`.
+    pub fn space_for(&self, kind: ResourceKind, tile_capacity: u32) -> u32 {
+        let kind_space = kind.cap().saturating_sub(self.get(kind));
+        let tile_space = tile_capacity.saturating_sub(self.total());
+        kind_space.min(tile_space)
+    }
+
+    /// Takes off whatever is over the caps and returns it.
+    fn remove_overflow(&mut self, tile_capacity: u32) -> Resource {
+        let mut overflow = Resource::default();
+        for kind in ResourceKind::ALL {
+            let over = self.get(kind).saturating_sub(kind.cap());
+            overflow.add(kind, self.take(kind, over));
+        }
+        // still too much in total. Lose the most plentiful kinds first
+        while self.total() > tile_capacity {
+            let most = ResourceKind::ALL
+                .into_iter()
+                .max_by_key(|&kind| self.get(kind))
+                .unwrap();
+            overflow.add(most, self.take(most, 1));
+        }
+        overflow
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.amounts.iter().all(|&amount| amount == 0)
+    }
+
+    /// Each kind there is some of, with how many.
+    pub fn iter(&self) -> impl Iterator<Item = (ResourceKind, u32)> + '_ {
+        ResourceKind::ALL
+            .into_iter()
+            .map(|kind| (kind, self.get(kind)))
+            .filter(|&(_, amount)| amount > 0)
+    }
+
+    pub fn can_afford(&self, cost: &Resource) -> bool {
+        ResourceKind::ALL
+            .iter()
+            .all(|&kind| self.get(kind) >= cost.get(kind))
+    }
+
+    /// Takes the whole cost if there's enough of everything. Takes nothing otherwise.
+    pub fn pay(&mut self, cost: &Resource) -> bool {
+        if !self.can_afford(cost) {
+            return false;
+        }
+        for (kind, amount) in cost.iter() {
+            self.take(kind, amount);
+        }
+        true
     }
 }
 
-#[derive(Component, Debug, Copy, Clone)]
-pub enum Resource {
-    None,
-    Gold(u32),
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "free");
+        }
+        let parts: Vec<String> = self
+            .iter()
+            .map(|(kind, amount)| format!("{} {}", amount, kind.name()))
+            .collect();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+fn is_over_cap(res: &Resource, tile_capacity: u32) -> bool {
+    res.total() > tile_capacity || res.iter().any(|(kind, amount)| amount > kind.cap())
+}
+
+fn enforce_caps(
+    mut q_tiles: Query<(Entity, &Tile, &mut Resource)>,
+    grid: Res<Grid>,
+    rules: Res<StorageRules>,
+) {
+    // (tile, what didn't fit)
+    let mut spills = Vec::new();
+    for (entity, tile, mut res) in &mut q_tiles {
+        // read first so untouched tiles don't get marked as changed
+        if !res.is_changed() || !is_over_cap(&res, rules.tile_capacity) {
+            continue;
+        }
+        let overflow = res.remove_overflow(rules.tile_capacity);
+        spills.push((entity, tile.coords, overflow));
+    }
+
+    if !rules.spill_to_neighbours {
+        return;
+    }
+    for (source, coords, mut overflow) in spills {
+        for neighbour in grid.get_diamond_ring(coords, 1).into_iter().flatten() {
+            if neighbour == source {
+                continue;
+            }
+            if let Ok((_, tile, mut res)) = q_tiles.get_mut(neighbour) {
+                if !matches!(tile.tile_type, TileType::Friendly) {
+                    continue;
+                }
+                for kind in ResourceKind::ALL {
+                    let moved = overflow
+                        .get(kind)
+                        .min(res.space_for(kind, rules.tile_capacity));
+                    if moved > 0 {
+                        overflow.take(kind, moved);
+                        res.add(kind, moved);
+                    }
+                }
+            }
+            if overflow.is_empty() {
+                break;
+            }
+        }
+        // anything left over is lost
+    }
+}
+
+fn decay_resources(
+    mut q_tiles: Query<(&Tile, &mut Resource)>,
+    mut timer: ResMut<DecayTimer>,
+    rules: Res<StorageRules>,
+    time: Res<Time>,
+) {
+    if rules.decay_interval.is_none() || !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (tile, mut res) in &mut q_tiles {
+        if matches!(tile.tile_type, TileType::Friendly) || res.is_empty() {
+            continue;
+        }
+        // lose one of each kind
+        for kind in ResourceKind::ALL {
+            res.take(kind, 1);
+        }
+    }
 }
 
 #[derive(Component)]
 struct ResourceVisual;
 
+/// The coin entities showing a tile's [`Resource`], bottom to top for each kind.
+/// Kept so stacks can grow and shrink instead of being rebuilt.
+#[derive(Component, Default)]
+pub struct ResourceStack {
+    coins: [Vec<Entity>; ResourceKind::ALL.len()],
+}
+
+// gap between the stacks of each kind
+const STACK_SPACING: f32 = 9.0;
+
 fn check_max(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    q_resources: Query<(Entity, Option<&Children>, &Resource), Changed<Resource>>,
-    q_visuals: Query<Entity, With<ResourceVisual>>,
+    mut cache: ResMut<VisualsCache>,
+    mut q_resources: Query<(Entity, &Resource, &mut ResourceStack), Changed<Resource>>,
+    mut q_visuals: Query<&mut Transform, With<ResourceVisual>>,
 ) {
-    for (parent, children, res) in &q_resources {
-        // destroy all children with ResourceVisual component
-        // I don't know how many resources got added
-        // so just delete and rebuild every time it changes
-        if let Some(children) = children {
-            for &child in children {
-                if q_visuals.contains(child) {
-                    commands.entity(parent).remove_children(&[child]);
-                    commands.entity(child).despawn();
-                }
+    for (parent, res, mut stack) in &mut q_resources {
+        // one stack per kind, side by side
+        let columns: Vec<ResourceKind> = res.iter().map(|(kind, _)| kind).collect();
+        let first_x = -0.5 * (columns.len() as f32 - 1.0) * STACK_SPACING;
+
+        for kind in ResourceKind::ALL {
+            let coins = &mut stack.coins[kind.index()];
+            let count = res.get(kind) as usize;
+
+            // take coins off the top
+            while coins.len() > count {
+                let coin = coins.pop().unwrap();
+                commands.entity(parent).remove_children(&[coin]);
+                commands.entity(coin).despawn();
             }
-        }
 
-        match res {
-            Resource::None => {}
-            Resource::Gold(count) => {
-                // build a tower of gold
-                for i in 0..*count {
-                    let child = commands
-                        .spawn_bundle(MaterialMesh2dBundle {
-                            mesh: meshes.add(shape::Circle::new(6.0).into()).into(),
-                            material: materials.add(ColorMaterial::from(Color::GOLD)),
-                            transform: Transform::from_translation(Vec3::new(
-                                0.0,
-                                1.0 * (1 + i) as f32,
-                                0.1 * (1 + i) as f32,
-                            )),
-                            ..default()
-                        })
-                        .insert(ResourceVisual)
-                        .id();
-
-                    commands.entity(parent).add_child(child);
+            let x = match columns.iter().position(|&k| k == kind) {
+                Some(column) => first_x + column as f32 * STACK_SPACING,
+                None => continue,
+            };
+            // other kinds coming or going moves the column
+            for &coin in coins.iter() {
+                if let Ok(mut trans) = q_visuals.get_mut(coin) {
+                    if trans.translation.x != x {
+                        trans.translation.x = x;
+                    }
                 }
             }
+
+            // build the tower of coins up
+            for i in coins.len()..count {
+                let child = commands
+                    .spawn_bundle(MaterialMesh2dBundle {
+                        mesh: cache.circle(&mut meshes, COIN_RADIUS),
+                        material: cache.material(&mut materials, kind.colour()),
+                        transform: Transform::from_translation(Vec3::new(
+                            x,
+                            1.0 * (1 + i) as f32,
+                            0.1 * (1 + i) as f32,
+                        )),
+                        ..default()
+                    })
+                    .insert(ResourceVisual)
+                    .id();
+
+                commands.entity(parent).add_child(child);
+                coins.push(child);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pay_takes_the_whole_cost() {
+        let mut stock = Resource::of(ResourceKind::Gold, 5).with(ResourceKind::Stone, 3);
+        let cost = Resource::of(ResourceKind::Gold, 2).with(ResourceKind::Stone, 3);
+        assert!(stock.pay(&cost));
+        assert_eq!(stock, Resource::of(ResourceKind::Gold, 3));
+    }
+
+    #[test]
+    fn pay_takes_nothing_when_short_of_one_kind() {
+        let mut stock = Resource::of(ResourceKind::Gold, 5).with(ResourceKind::Mana, 1);
+        let before = stock;
+        let cost = Resource::of(ResourceKind::Gold, 2).with(ResourceKind::Mana, 2);
+        assert!(!stock.pay(&cost));
+        assert_eq!(stock, before);
+    }
+
+    #[test]
+    fn free_costs_nothing() {
+        let mut stock = Resource::default();
+        assert!(stock.pay(&Resource::default()));
+        assert!(stock.is_empty());
+    }
+
+    #[test]
+    fn space_for_is_the_tighter_of_both_caps() {
+        let empty = Resource::default();
+        assert_eq!(
+            empty.space_for(ResourceKind::Gold, 16),
+            ResourceKind::Gold.cap()
+        );
+        assert_eq!(empty.space_for(ResourceKind::Gold, 3), 3);
+
+        // kind cap is tighter
+        let res = Resource::of(ResourceKind::Gold, ResourceKind::Gold.cap() - 1);
+        assert_eq!(res.space_for(ResourceKind::Gold, 100), 1);
+
+        // tile cap is tighter
+        let res = Resource::of(ResourceKind::Stone, 4).with(ResourceKind::Mana, 3);
+        assert_eq!(res.space_for(ResourceKind::Gold, 9), 2);
+    }
+
+    #[test]
+    fn space_for_a_full_or_overfull_tile_is_zero() {
+        let full = Resource::of(ResourceKind::Mana, ResourceKind::Mana.cap());
+        assert_eq!(full.space_for(ResourceKind::Mana, 100), 0);
+
+        let over = Resource::of(ResourceKind::Gold, 20);
+        assert_eq!(over.space_for(ResourceKind::Gold, 16), 0);
+        assert_eq!(over.space_for(ResourceKind::Stone, 16), 0);
+    }
+
+    #[test]
+    fn overflow_leaves_the_tile_within_its_caps() {
+        let gold_cap = ResourceKind::Gold.cap();
+        let stone_cap = ResourceKind::Stone.cap();
+        let mut res =
+            Resource::of(ResourceKind::Gold, gold_cap + 2).with(ResourceKind::Stone, stone_cap);
+        let before = res.total();
+        let capacity = gold_cap + stone_cap - 2;
+        let overflow = res.remove_overflow(capacity);
+
+        assert!(!is_over_cap(&res, capacity));
+        assert_eq!(res.total(), capacity);
+        // nothing goes missing
+        assert_eq!(res.total() + overflow.total(), before);
+    }
+}
@@ -0,0 +1,217 @@
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::{
+    grid::{Coords, Grid, Selection, Tile},
+    history::{GridAction, RecordActionEvent},
+    loading::FontAssets,
+    wall::PlaceWallEvent,
+    GameState, MouseWorldPos,
+};
+
+pub struct SelectionPlugin;
+
+impl Plugin for SelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_exit(GameState::Loading).with_system(create_selection_ui));
+        app.insert_resource(InputMode::Swap)
+            .insert_resource(BoxSelect::default())
+            .add_system(switch_input_mode.before(box_select))
+            .add_system(box_select.before(crate::grid::clear_selection))
+            .add_system(
+                preview_box_select
+                    .after(crate::grid::check_interaction)
+                    .before(crate::grid::tile_interaction),
+            )
+            .add_system(update_selection_ui);
+    }
+}
+
+/// What clicking and dragging on the grid does. Tab cycles through them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputMode {
+    // drag a tile onto its neighbour to swap them
+    Swap,
+    // drag a box to select. Shift adds to the selection, ctrl toggles
+    Select,
+    // drag a line to build walls along it
+    PaintWalls,
+}
+
+impl InputMode {
+    fn next(&self) -> Self {
+        match self {
+            InputMode::Swap => InputMode::Select,
+            InputMode::Select => InputMode::PaintWalls,
+            InputMode::PaintWalls => InputMode::Swap,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            InputMode::Swap => "swap",
+            InputMode::Select => "select",
+            InputMode::PaintWalls => "paint walls",
+        }
+    }
+
+    /// The tiles covered by dragging from `start` to `end`.
+    fn dragged_tiles(&self, grid: &Grid, start: Coords, end: Coords) -> Vec<Entity> {
+        match self {
+            InputMode::Swap => Vec::new(),
+            InputMode::Select => grid.get_rect(start, end),
+            InputMode::PaintWalls => grid.get_line(start, end),
+        }
+    }
+}
+
+/// Where the current box or line started. None when not dragging.
+#[derive(Default)]
+struct BoxSelect {
+    start: Option<Coords>,
+}
+
+fn switch_input_mode(
+    keyboard: Res<Input<KeyCode>>,
+    mut mode: ResMut<InputMode>,
+    mut drag: ResMut<BoxSelect>,
+) {
+    if keyboard.just_pressed(KeyCode::Tab) {
+        *mode = mode.next();
+        drag.start = None;
+    }
+}
+
+fn box_select(
+    mut commands: Commands,
+    mouse: Res<MouseWorldPos>,
+    mouse_click: Res<Input<MouseButton>>,
+    keyboard: Res<Input<KeyCode>>,
+    mode: Res<InputMode>,
+    grid: Res<Grid>,
+    mut drag: ResMut<BoxSelect>,
+    q_tiles: Query<(Entity, &Tile, Option<&Selection>)>,
+    mut ev_wall: EventWriter<PlaceWallEvent>,
+    mut ev_record: EventWriter<RecordActionEvent>,
+) {
+    if *mode == InputMode::Swap {
+        return;
+    }
+    if mouse_click.just_pressed(MouseButton::Left) {
+        drag.start = grid.layout().tile_at(mouse.0);
+    }
+    if !mouse_click.just_released(MouseButton::Left) {
+        return;
+    }
+    let start = match drag.start.take() {
+        Some(start) => start,
+        None => return,
+    };
+    // the mouse can be let go off the edge of the grid
+    let end = grid.layout().clamped_coords(mouse.0);
+    let tiles = mode.dragged_tiles(&grid, start, end);
+
+    match *mode {
+        InputMode::Select => {
+            let add = keyboard.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+            let toggle = keyboard.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+            let in_box: HashSet<Entity> = tiles.iter().copied().collect();
+            for (entity, _, selected) in q_tiles.iter() {
+                let selected = selected.is_some();
+                let keep = if toggle {
+                    selected != in_box.contains(&entity)
+                } else if add {
+                    selected || in_box.contains(&entity)
+                } else {
+                    in_box.contains(&entity)
+                };
+                if keep && !selected {
+                    commands.entity(entity).insert(Selection);
+                } else if !keep && selected {
+                    commands.entity(entity).remove::<Selection>();
+                }
+            }
+        }
+        InputMode::PaintWalls => {
+            for entity in tiles {
+                match q_tiles.get(entity) {
+                    // already has a wall or tower, or the ground won't take one
+                    Ok((_, tile, _)) if tile.can_build() => {}
+                    _ => continue,
+                }
+                ev_wall.send(PlaceWallEvent { tile: entity });
+                ev_record.send(RecordActionEvent(GridAction::Wall { tile: entity }));
+            }
+        }
+        InputMode::Swap => {}
+    }
+}
+
+// highlights the tiles the drag covers so far
+fn preview_box_select(
+    mouse: Res<MouseWorldPos>,
+    mode: Res<InputMode>,
+    grid: Res<Grid>,
+    drag: Res<BoxSelect>,
+    mut q_tiles: Query<&mut Interaction, With<Tile>>,
+) {
+    let start = match drag.start {
+        Some(start) => start,
+        None => return,
+    };
+    let end = grid.layout().clamped_coords(mouse.0);
+    for entity in mode.dragged_tiles(&grid, start, end) {
+        if let Ok(mut interaction) = q_tiles.get_mut(entity) {
+            if *interaction == Interaction::None {
+                *interaction = Interaction::Hovered;
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+struct SelectionText;
+
+fn create_selection_ui(mut commands: Commands, fonts: Res<FontAssets>) {
+    let style = TextStyle {
+        font: fonts.fira_sans.clone(),
+        font_size: 20.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(
+            // mode, then how many tiles are selected
+            TextBundle::from_sections([
+                TextSection::new("", style.clone()),
+                TextSection::new("", style),
+            ])
+            .with_style(Style {
+                // under the treasury
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(8.0),
+                    top: Val::Px(36.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(SelectionText);
+}
+
+fn update_selection_ui(
+    mode: Res<InputMode>,
+    q_selection: Query<(), With<Selection>>,
+    mut q_text: Query<&mut Text, With<SelectionText>>,
+) {
+    let labels = [
+        format!("mode: {} (tab)  ", mode.name()),
+        format!("selected: {}", q_selection.iter().count()),
+    ];
+    for mut text in &mut q_text {
+        for (section, label) in text.sections.iter_mut().zip(&labels) {
+            if section.value != *label {
+                section.value = label.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    enemy::{Enemy, MovementLayer, TargetLayers},
+    grid::GridLayout,
+};
+
+pub struct SpatialPlugin;
+
+impl Plugin for SpatialPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(EnemySpatialIndex::new(GridLayout::default().tile_size))
+            // rebuilt before anything in Update asks where enemies are
+            .add_system_to_stage(CoreStage::PreUpdate, rebuild_enemy_index);
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct IndexedEnemy {
+    entity: Entity,
+    pos: Vec2,
+    radius: f32,
+    layer: MovementLayer,
+}
+
+/// Uniform grid of buckets holding where every enemy is this frame.
+/// Cells are a tile across, since most queries are a tile or two across.
+/// Used instead of a physics engine for hit detection and targeting.
+///
+/// Queries test against the enemy's radius, so a point query of radius 0.0
+/// finds enemies overlapping that point.
+/// Queries only return enemies on the given [`TargetLayers`].
+pub struct EnemySpatialIndex {
+    cell_size: f32,
+    enemies: Vec<IndexedEnemy>,
+    // cell -> indices into enemies
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl EnemySpatialIndex {
+    pub fn new(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "cell_size needs to be above 0");
+        EnemySpatialIndex {
+            cell_size,
+            enemies: Vec::new(),
+            cells: HashMap::default(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.enemies.clear();
+        // keep the buckets around so they don't reallocate every frame
+        for bucket in self.cells.values_mut() {
+            bucket.clear();
+        }
+    }
+
+    pub fn insert(&mut self, entity: Entity, pos: Vec2, radius: f32, layer: MovementLayer) {
+        let index = self.enemies.len();
+        self.enemies.push(IndexedEnemy {
+            entity,
+            pos,
+            radius,
+            layer,
+        });
+        let cell = self.cell(pos);
+        self.cells.entry(cell).or_default().push(index);
+    }
+
+    fn cell(&self, pos: Vec2) -> (i32, i32) {
+        (
+            (pos.x / self.cell_size).floor() as i32,
+            (pos.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    /// All enemies in the cells within `range` cells of `center`.
+    fn enemies_near_cell(
+        &self,
+        center: (i32, i32),
+        range: i32,
+    ) -> impl Iterator<Item = &IndexedEnemy> {
+        (center.0 - range..=center.0 + range)
+            .flat_map(move |x| (center.1 - range..=center.1 + range).map(move |y| (x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .map(move |&i| &self.enemies[i])
+    }
+
+    /// How many cells out a search needs to go to find everything within `radius`.
+    /// Enemies are bucketed by their center, so their radius needs to be covered too.
+    fn cell_range(&self, radius: f32) -> i32 {
+        ((radius + self.cell_size) / self.cell_size).ceil() as i32
+    }
+
+    /// Every enemy overlapping the circle at `pos`.
+    pub fn enemies_in_radius(
+        &self,
+        pos: Vec2,
+        radius: f32,
+        layers: TargetLayers,
+    ) -> Vec<(Entity, Vec2)> {
+        self.enemies_near_cell(self.cell(pos), self.cell_range(radius))
+            .filter(|e| layers.contains(e.layer))
+            .filter(|e| {
+                let reach = radius + e.radius;
+                e.pos.distance_squared(pos) < reach * reach
+            })
+            .map(|e| (e.entity, e.pos))
+            .collect()
+    }
+
+    /// Closest enemy center to `pos` within `max_distance` that passes `filter`.
+    pub fn nearest_enemy(
+        &self,
+        pos: Vec2,
+        max_distance: f32,
+        layers: TargetLayers,
+        mut filter: impl FnMut(Entity) -> bool,
+    ) -> Option<(Entity, Vec2)> {
+        let center = self.cell(pos);
+        let max_range = self.cell_range(max_distance);
+        let mut nearest: Option<(f32, &IndexedEnemy)> = None;
+
+        // search outwards one ring at a time
+        for range in 0..=max_range {
+            for cell in ring_cells(center, range) {
+                let bucket = match self.cells.get(&cell) {
+                    Some(bucket) => bucket,
+                    None => continue,
+                };
+                for &i in bucket {
+                    let e = &self.enemies[i];
+                    let dist = e.pos.distance_squared(pos);
+                    if dist > max_distance * max_distance || !layers.contains(e.layer) {
+                        continue;
+                    }
+                    let closer = match nearest {
+                        Some((best, _)) => dist < best,
+                        None => true,
+                    };
+                    if closer && filter(e.entity) {
+                        nearest = Some((dist, e));
+                    }
+                }
+            }
+
+            // anything in the next ring is at least this far away
+            if let Some((best, _)) = nearest {
+                let ring_dist = range as f32 * self.cell_size;
+                if best <= ring_dist * ring_dist {
+                    break;
+                }
+            }
+        }
+
+        nearest.map(|(_, e)| (e.entity, e.pos))
+    }
+
+    /// First enemy along the line from `start` to `end`, for a shot `width` wide.
+    /// Returns the enemy and the point on the line where it was hit.
+    pub fn first_enemy_on_segment(
+        &self,
+        start: Vec2,
+        end: Vec2,
+        width: f32,
+        layers: TargetLayers,
+    ) -> Option<(Entity, Vec2)> {
+        let mut hit = None;
+        let mut closest_t = f32::MAX;
+        for e in self
+            .enemies_near_segment(start, end, width)
+            .filter(|e| layers.contains(e.layer))
+        {
+            if let Some(t) = segment_hit(start, end, e.pos, e.radius + width * 0.5) {
+                if t < closest_t {
+                    closest_t = t;
+                    hit = Some((e.entity, start.lerp(end, t)));
+                }
+            }
+        }
+        hit
+    }
+
+    fn enemies_near_segment(
+        &self,
+        start: Vec2,
+        end: Vec2,
+        width: f32,
+    ) -> impl Iterator<Item = &IndexedEnemy> {
+        let range = self.cell_range(width * 0.5);
+        let mut cells = HashSet::default();
+
+        // walk along the line in half cell steps so no cell gets skipped
+        let length = start.distance(end);
+        let steps = (length / (self.cell_size * 0.5)).ceil() as i32;
+        for i in 0..=steps {
+            let t = if steps == 0 {
+                0.0
+            } else {
+                i as f32 / steps as f32
+            };
+            let (cx, cy) = self.cell(start.lerp(end, t));
+            for x in cx - range..=cx + range {
+                for y in cy - range..=cy + range {
+                    cells.insert((x, y));
+                }
+            }
+        }
+
+        cells
+            .into_iter()
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .map(move |&i| &self.enemies[i])
+    }
+}
+
+/// The hollow square of cells `range` cells away from `center`.
+fn ring_cells(center: (i32, i32), range: i32) -> Vec<(i32, i32)> {
+    if range == 0 {
+        return vec![center];
+    }
+
+    let (x, y) = center;
+    let mut v = Vec::new();
+    // top and bottom rows
+    for i in -range..=range {
+        v.push((x + i, y + range));
+        v.push((x + i, y - range));
+    }
+    // left and right columns without the corners
+    for j in (-range + 1)..range {
+        v.push((x - range, y + j));
+        v.push((x + range, y + j));
+    }
+    v
+}
+
+/// Percent along the segment where it gets within `radius` of `pos`.
+fn segment_hit(start: Vec2, end: Vec2, pos: Vec2, radius: f32) -> Option<f32> {
+    let line = end - start;
+    let length_squared = line.length_squared();
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        ((pos - start).dot(line) / length_squared).clamp(0.0, 1.0)
+    };
+    if start.lerp(end, t).distance_squared(pos) < radius * radius {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+fn rebuild_enemy_index(
+    mut index: ResMut<EnemySpatialIndex>,
+    q_enemies: Query<(Entity, &Transform, &Enemy)>,
+    layout: Res<GridLayout>,
+) {
+    if layout.is_changed() && index.cell_size != layout.tile_size {
+        *index = EnemySpatialIndex::new(layout.tile_size);
+    }
+    index.clear();
+    for (entity, trans, enemy) in &q_enemies {
+        index.insert(
+            entity,
+            trans.translation.truncate(),
+            enemy.radius(),
+            enemy.layer(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_with(enemies: &[(u32, Vec2, MovementLayer)]) -> EnemySpatialIndex {
+        let mut index = EnemySpatialIndex::new(32.0);
+        for &(id, pos, layer) in enemies {
+            index.insert(Entity::from_raw(id), pos, 4.0, layer);
+        }
+        index
+    }
+
+    #[test]
+    fn nearest_picks_the_closest() {
+        let index = index_with(&[
+            (0, Vec2::new(50.0, 0.0), MovementLayer::Ground),
+            (1, Vec2::new(20.0, 5.0), MovementLayer::Ground),
+            (2, Vec2::new(-90.0, 0.0), MovementLayer::Ground),
+        ]);
+        let nearest = index.nearest_enemy(Vec2::ZERO, 200.0, TargetLayers::ALL, |_| true);
+        assert_eq!(nearest.map(|(e, _)| e), Some(Entity::from_raw(1)));
+    }
+
+    #[test]
+    fn nearest_looks_past_its_own_cell() {
+        // the enemy in the same cell is further than the one just over the border
+        let index = index_with(&[
+            (0, Vec2::new(1.0, 1.0), MovementLayer::Ground),
+            (1, Vec2::new(33.0, 1.0), MovementLayer::Ground),
+        ]);
+        let nearest = index.nearest_enemy(Vec2::new(31.0, 1.0), 200.0, TargetLayers::ALL, |_| true);
+        assert_eq!(nearest.map(|(e, _)| e), Some(Entity::from_raw(1)));
+    }
+
+    #[test]
+    fn nearest_respects_max_distance() {
+        let index = index_with(&[(0, Vec2::new(100.0, 0.0), MovementLayer::Ground)]);
+        assert!(index
+            .nearest_enemy(Vec2::ZERO, 99.0, TargetLayers::ALL, |_| true)
+            .is_none());
+        assert!(index
+            .nearest_enemy(Vec2::ZERO, 101.0, TargetLayers::ALL, |_| true)
+            .is_some());
+    }
+
+    #[test]
+    fn nearest_skips_filtered_and_other_layers() {
+        let index = index_with(&[
+            (0, Vec2::new(10.0, 0.0), MovementLayer::Air),
+            (1, Vec2::new(20.0, 0.0), MovementLayer::Ground),
+            (2, Vec2::new(30.0, 0.0), MovementLayer::Ground),
+        ]);
+        let ground = index.nearest_enemy(Vec2::ZERO, 200.0, TargetLayers::GROUND, |_| true);
+        assert_eq!(ground.map(|(e, _)| e), Some(Entity::from_raw(1)));
+
+        let filtered = index.nearest_enemy(Vec2::ZERO, 200.0, TargetLayers::ALL, |e| {
+            e != Entity::from_raw(0) && e != Entity::from_raw(1)
+        });
+        assert_eq!(filtered.map(|(e, _)| e), Some(Entity::from_raw(2)));
+    }
+
+    #[test]
+    fn nearest_in_an_empty_index() {
+        let index = index_with(&[]);
+        assert!(index
+            .nearest_enemy(Vec2::ZERO, 1000.0, TargetLayers::ALL, |_| true)
+            .is_none());
+    }
+
+    #[test]
+    fn cleared_index_forgets_enemies() {
+        let mut index = index_with(&[(0, Vec2::ZERO, MovementLayer::Ground)]);
+        index.clear();
+        assert!(index
+            .enemies_in_radius(Vec2::ZERO, 10.0, TargetLayers::ALL)
+            .is_empty());
+    }
+}
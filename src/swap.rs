@@ -1,8 +1,16 @@
+use std::collections::VecDeque;
+
 use bevy::prelude::*;
 
 use crate::{
-    grid::{Coords, Grid, Tile, TILE_SIZE},
-    resource_container::Resource,
+    enemy::TargetLayers,
+    flow_field::{FlowFieldGoal, RecalculateFlowFieldEvent},
+    gold::Treasury,
+    grid::{Coords, Grid, GridLayout, Terrain, TerritoryFlipper, Tile, TileType, STRUCTURE_COST},
+    history::{GridAction, RecordActionEvent},
+    resource_container::{Resource, ResourceKind},
+    selection::InputMode,
+    spatial::EnemySpatialIndex,
     MouseWorldPos,
 };
 
@@ -10,7 +18,9 @@ pub struct SwapPlugin;
 
 impl Plugin for SwapPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<SwapEvent>()
+        app.insert_resource(SwapRules::default())
+            .init_resource::<SwapAnimation>()
+            .add_event::<SwapEvent>()
             .add_system(start_drag)
             .add_system(drop.after(drag_selection))
             .add_system(drag_selection)
@@ -18,7 +28,11 @@ impl Plugin for SwapPlugin {
             .add_system(swap_event_combine_resources)
             .add_system(swap_event_update_drag_home)
             .add_system(swap_event_update_tile_coords)
-            .add_system(swap_event_update_grid);
+            .add_system(swap_event_update_grid)
+            .add_system(swap_event_start_cooldown)
+            .add_system(tick_swap_cooldowns)
+            .add_system(fade_reject_flashes)
+            .add_system(tick_swap_tweens);
     }
 }
 
@@ -33,23 +47,161 @@ impl Draggable {
     }
 }
 
+#[derive(Component, Default)]
+struct Dragged {
+    // last tile it wasn't allowed to swap with. Only flash it once
+    rejected: Option<Entity>,
+}
+
+/// What's allowed when swapping two tiles.
+pub struct SwapRules {
+    pub gold_cost: u32,
+    // seconds before a tile can be swapped again
+    pub cooldown: f32,
+    pub allow_hostile: bool,
+    // swaps that cut open tiles off from the goal
+    pub allow_path_blocking: bool,
+    // at least one tile needs a structure or resources on it
+    pub only_loaded_tiles: bool,
+    // with only_loaded_tiles, terrain other than grass counts as a load too
+    pub terrain_is_loaded: bool,
+}
+
+impl Default for SwapRules {
+    fn default() -> Self {
+        SwapRules {
+            gold_cost: 0,
+            cooldown: 1.0,
+            allow_hostile: false,
+            allow_path_blocking: false,
+            only_loaded_tiles: false,
+            terrain_is_loaded: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SwapRejection {
+    Cooldown,
+    Flipping,
+    EnemyOnTile,
+    Hostile,
+    Empty,
+    BlocksPath,
+    CantAfford,
+}
+
 #[derive(Component)]
-struct Dragged;
+struct SwapCooldown(Timer);
+
+/// Whether swapping the last pair checked would cut off the path.
+/// Saves rerunning the search every frame while the same pair is hovered.
+#[derive(Default)]
+struct PathBlockCache {
+    pair: Option<[Entity; 2]>,
+    blocks: bool,
+}
+
+/// How tiles slide back into place after a swap or drop.
+pub struct SwapAnimation {
+    pub duration: f32,
+    pub easing: Easing,
+}
+
+impl Default for SwapAnimation {
+    fn default() -> Self {
+        SwapAnimation {
+            duration: 0.25,
+            easing: Easing::CubicOut,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    QuadInOut,
+    CubicOut,
+    // overshoots a little then settles
+    BackOut,
+}
+
+impl Easing {
+    /// Maps 0.0 - 1.0 of the time to 0.0 - 1.0 of the distance.
+    pub fn apply(&self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::QuadInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (-2.0 * t + 2.0).powi(2) * 0.5
+                }
+            }
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::BackOut => {
+                let c1 = 1.70158;
+                let c3 = c1 + 1.0;
+                1.0 + c3 * (t - 1.0).powi(3) + c1 * (t - 1.0).powi(2)
+            }
+        }
+    }
+}
 
-struct SwapEvent {
-    from: Coords,
-    to: Coords,
-    from_ent: Entity,
-    to_ent: Entity,
+/// Slides a tile to `end`. Tiles can't be picked up or selected until it's done,
+/// like tiles with a TerritoryFlipper.
+#[derive(Component)]
+pub struct SwapTween {
+    start: Vec3,
+    end: Vec3,
+    timer: Timer,
+    easing: Easing,
+}
+
+impl SwapTween {
+    fn new(start: Vec3, end: Vec3, animation: &SwapAnimation) -> Self {
+        SwapTween {
+            start,
+            end,
+            timer: Timer::from_seconds(animation.duration, false),
+            easing: animation.easing,
+        }
+    }
+}
+
+fn tick_swap_tweens(
+    mut commands: Commands,
+    mut q_tweens: Query<(Entity, &mut Transform, &mut SwapTween)>,
+    time: Res<Time>,
+) {
+    for (entity, mut trans, mut tween) in &mut q_tweens {
+        if tween.timer.tick(time.delta()).finished() {
+            trans.translation = tween.end;
+            commands.entity(entity).remove::<SwapTween>();
+            continue;
+        }
+        let t = tween.easing.apply(tween.timer.percent());
+        trans.translation = tween.start.lerp(tween.end, t);
+    }
+}
+
+pub struct SwapEvent {
+    pub from: Coords,
+    pub to: Coords,
+    pub from_ent: Entity,
+    pub to_ent: Entity,
+    // undo or redo. Resources are set by the history and nothing is being dragged
+    pub replay: bool,
 }
 
 fn start_drag(
     mut commands: Commands,
     mouse: Res<MouseWorldPos>,
     mouse_click: Res<Input<MouseButton>>,
-    q_draggable: Query<(Entity, &Transform, &Sprite), With<Draggable>>,
+    q_draggable: Query<(Entity, &Transform, &Sprite), (With<Draggable>, Without<SwapTween>)>,
+    mode: Res<InputMode>,
 ) {
-    if mouse_click.just_pressed(MouseButton::Left) {
+    if *mode == InputMode::Swap && mouse_click.just_pressed(MouseButton::Left) {
         for (entity, trans, sprite) in &q_draggable {
             // is the mouse inside this object?
 
@@ -63,7 +215,7 @@ fn start_drag(
                 {
                     // mouse is inside
                     // println!("Mouse selected something");
-                    commands.entity(entity).insert(Dragged);
+                    commands.entity(entity).insert(Dragged::default());
                 }
             }
         }
@@ -73,13 +225,17 @@ fn start_drag(
 fn drop(
     mut commands: Commands,
     mouse_click: Res<Input<MouseButton>>,
-    mut q_dragged: Query<(Entity, &mut Transform, &Draggable), With<Dragged>>,
+    q_dragged: Query<(Entity, &Transform, &Draggable), With<Dragged>>,
+    animation: Res<SwapAnimation>,
 ) {
     if mouse_click.just_released(MouseButton::Left) {
-        for (entity, mut trans, drag) in &mut q_dragged {
+        for (entity, trans, drag) in &q_dragged {
             // println!("Dropped {:?} at {:?}", entity, trans.translation);
-            trans.translation = drag.home;
-            commands.entity(entity).remove::<Dragged>();
+            // slide back home
+            commands
+                .entity(entity)
+                .remove::<Dragged>()
+                .insert(SwapTween::new(trans.translation, drag.home, &animation));
         }
     }
 }
@@ -87,9 +243,10 @@ fn drop(
 fn drag_selection(
     mouse: Res<MouseWorldPos>,
     mut q_dragged: Query<(&mut Transform, &Draggable), With<Dragged>>,
+    layout: Res<GridLayout>,
 ) {
     for (mut trans, drag) in &mut q_dragged {
-        let distance = Vec2::ONE * TILE_SIZE * 0.55;
+        let distance = Vec2::ONE * layout.tile_size * 0.55;
         let leash_pos = mouse.0.clamp(
             drag.home.truncate() - distance,
             drag.home.truncate() + distance,
@@ -99,29 +256,292 @@ fn drag_selection(
 }
 
 fn swap_selection(
-    q_dragged: Query<(Entity, &Transform, &Tile), (With<Draggable>, With<Dragged>)>,
-    q_draggable: Query<(Entity, &Tile), (With<Draggable>, Without<Dragged>)>,
+    mut commands: Commands,
+    mut q_dragged: Query<(Entity, &Transform, &mut Dragged), With<Draggable>>,
+    q_draggable: Query<Entity, (With<Draggable>, Without<Dragged>, Without<SwapTween>)>,
+    q_tiles: Query<(
+        &Tile,
+        &Resource,
+        Option<&SwapCooldown>,
+        Option<&TerritoryFlipper>,
+    )>,
     grid: Res<Grid>,
+    goal: Res<FlowFieldGoal>,
+    index: Res<EnemySpatialIndex>,
+    rules: Res<SwapRules>,
+    mut treasury: ResMut<Treasury>,
     mut ev_swap: EventWriter<SwapEvent>,
+    mut ev_record: EventWriter<RecordActionEvent>,
+    mut path_cache: Local<PathBlockCache>,
+    q_changed_tiles: Query<(), Changed<Tile>>,
 ) {
-    for (drag_ent, drag_trans, drag_tile) in &q_dragged {
-        let coords = Coords::from_vec2(drag_trans.translation.truncate());
-        if let Some(entity) = grid.get_coords(coords) {
-            if let Ok((other_ent, other_tile)) = q_draggable.get(entity) {
+    // costs or the goal moved, so the old answer might be wrong
+    if grid.is_changed() || goal.is_changed() || !q_changed_tiles.is_empty() {
+        path_cache.pair = None;
+    }
+    for (drag_ent, drag_trans, mut dragged) in &mut q_dragged {
+        let coords = grid.layout().coords(drag_trans.translation.truncate());
+        let other_ent = match grid.get_coords(coords) {
+            Some(entity) if q_draggable.contains(entity) => entity,
+            _ => continue,
+        };
+        let (drag_tile, other_tile, before) = match (q_tiles.get(drag_ent), q_tiles.get(other_ent))
+        {
+            (Ok((drag_tile, drag_res, ..)), Ok((other_tile, other_res, ..))) => {
+                (*drag_tile, *other_tile, [*drag_res, *other_res])
+            }
+            _ => continue,
+        };
+        let cost = Resource::of(ResourceKind::Gold, rules.gold_cost);
+
+        let result = check_swap(
+            [drag_ent, other_ent],
+            &q_tiles,
+            &grid,
+            &goal,
+            &index,
+            &rules,
+            &mut path_cache,
+        )
+        .and_then(|_| {
+            if treasury.stock.pay(&cost) {
+                Ok(())
+            } else {
+                Err(SwapRejection::CantAfford)
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                dragged.rejected = None;
                 ev_swap.send(SwapEvent {
                     from: drag_tile.coords,
                     to: other_tile.coords,
                     from_ent: drag_ent,
                     to_ent: other_ent,
+                    replay: false,
                 });
+                ev_record.send(RecordActionEvent(GridAction::Swap {
+                    tiles: [drag_ent, other_ent],
+                    before,
+                    after: combine_resources(before),
+                    cost,
+                }));
+            }
+            Err(_) => {
+                if dragged.rejected != Some(other_ent) {
+                    dragged.rejected = Some(other_ent);
+                    spawn_reject_flash(&mut commands, other_ent, grid.layout().tile_size);
+                }
+            }
+        }
+    }
+}
+
+fn check_swap(
+    tiles: [Entity; 2],
+    q_tiles: &Query<(
+        &Tile,
+        &Resource,
+        Option<&SwapCooldown>,
+        Option<&TerritoryFlipper>,
+    )>,
+    grid: &Grid,
+    goal: &FlowFieldGoal,
+    index: &EnemySpatialIndex,
+    rules: &SwapRules,
+    path_cache: &mut PathBlockCache,
+) -> Result<(), SwapRejection> {
+    let mut loaded = false;
+    for entity in tiles {
+        let (tile, res, cooldown, flipper) = match q_tiles.get(entity) {
+            Ok(info) => info,
+            Err(_) => return Err(SwapRejection::Empty),
+        };
+        if cooldown.is_some() {
+            return Err(SwapRejection::Cooldown);
+        }
+        if flipper.is_some() {
+            return Err(SwapRejection::Flipping);
+        }
+        if !index
+            .enemies_in_radius(
+                grid.layout().pos(tile.coords),
+                grid.layout().tile_size * 0.5,
+                TargetLayers::GROUND,
+            )
+            .is_empty()
+        {
+            return Err(SwapRejection::EnemyOnTile);
+        }
+        if !rules.allow_hostile && matches!(tile.tile_type, TileType::Hostile) {
+            return Err(SwapRejection::Hostile);
+        }
+        loaded |= tile.cost >= STRUCTURE_COST || !res.is_empty();
+        loaded |= rules.terrain_is_loaded && tile.terrain != Terrain::Grass;
+    }
+    if rules.only_loaded_tiles && !loaded {
+        return Err(SwapRejection::Empty);
+    }
+
+    if !rules.allow_path_blocking {
+        if let Some(goal) = goal.0 {
+            if path_cache.pair != Some(tiles) {
+                path_cache.pair = Some(tiles);
+                path_cache.blocks = swap_blocks_path(tiles, q_tiles, grid, goal);
+            }
+            if path_cache.blocks {
+                return Err(SwapRejection::BlocksPath);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether swapping `tiles` leaves more open tiles cut off from `goal` than before.
+fn swap_blocks_path(
+    tiles: [Entity; 2],
+    q_tiles: &Query<(
+        &Tile,
+        &Resource,
+        Option<&SwapCooldown>,
+        Option<&TerritoryFlipper>,
+    )>,
+    grid: &Grid,
+    goal: Coords,
+) -> bool {
+    let layout = grid.layout();
+    let mut costs = vec![1; layout.tile_count()];
+    for &entity in grid.tiles.iter() {
+        if let Ok((tile, ..)) = q_tiles.get(entity) {
+            costs[layout.index(tile.coords.x, tile.coords.y)] = tile.cost;
+        }
+    }
+    let before = cut_off_tiles(layout, &costs, goal);
+    if let (Ok((a, ..)), Ok((b, ..))) = (q_tiles.get(tiles[0]), q_tiles.get(tiles[1])) {
+        costs.swap(
+            layout.index(a.coords.x, a.coords.y),
+            layout.index(b.coords.x, b.coords.y),
+        );
+    }
+    cut_off_tiles(layout, &costs, goal) > before
+}
+
+/// How many open tiles can't reach `goal` without going through a structure.
+/// `costs` is indexed by [`GridLayout::index`].
+fn cut_off_tiles(layout: &GridLayout, costs: &[u8], goal: Coords) -> usize {
+    let is_open = |i: usize| costs[i] < STRUCTURE_COST;
+    let mut reached = vec![false; costs.len()];
+    let mut queue = VecDeque::new();
+    let start = layout.index(goal.x, goal.y);
+    reached[start] = true;
+    queue.push_back(start);
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = layout.xy(i);
+        let mut neighbours = Vec::new();
+        if x > 0 {
+            neighbours.push(layout.index(x - 1, y));
+        }
+        if x < layout.width - 1 {
+            neighbours.push(layout.index(x + 1, y));
+        }
+        if y > 0 {
+            neighbours.push(layout.index(x, y - 1));
+        }
+        if y < layout.height - 1 {
+            neighbours.push(layout.index(x, y + 1));
+        }
+        for n in neighbours {
+            if !reached[n] && is_open(n) {
+                reached[n] = true;
+                queue.push_back(n);
             }
         }
     }
+    (0..costs.len())
+        .filter(|&i| is_open(i) && !reached[i])
+        .count()
+}
+
+fn swap_event_start_cooldown(
+    mut commands: Commands,
+    mut ev_swap: EventReader<SwapEvent>,
+    rules: Res<SwapRules>,
+) {
+    if rules.cooldown <= 0.0 {
+        return;
+    }
+    for ev in ev_swap.iter().filter(|ev| !ev.replay) {
+        for entity in [ev.from_ent, ev.to_ent] {
+            commands
+                .entity(entity)
+                .insert(SwapCooldown(Timer::from_seconds(rules.cooldown, false)));
+        }
+    }
+}
+
+fn tick_swap_cooldowns(
+    mut commands: Commands,
+    mut q_cooldowns: Query<(Entity, &mut SwapCooldown)>,
+    time: Res<Time>,
+) {
+    for (entity, mut cooldown) in &mut q_cooldowns {
+        if cooldown.0.tick(time.delta()).just_finished() {
+            commands.entity(entity).remove::<SwapCooldown>();
+        }
+    }
 }
 
-fn swap_event_update_grid(mut grid: ResMut<Grid>, mut ev_swap: EventReader<SwapEvent>) {
+/// Red flash over a tile that can't be swapped with.
+#[derive(Component)]
+struct RejectFlash {
+    lifetime: Timer,
+}
+
+fn spawn_reject_flash(commands: &mut Commands, tile: Entity, tile_size: f32) {
+    let flash = commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgba(1.0, 0.0, 0.0, 0.5),
+                custom_size: Some(Vec2::splat(tile_size)),
+                ..default()
+            },
+            transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.6)),
+            ..default()
+        })
+        .insert(RejectFlash {
+            lifetime: Timer::from_seconds(0.4, false),
+        })
+        .id();
+    commands.entity(tile).add_child(flash);
+}
+
+fn fade_reject_flashes(
+    mut commands: Commands,
+    mut q_flashes: Query<(Entity, &mut Sprite, &mut RejectFlash)>,
+    time: Res<Time>,
+) {
+    for (entity, mut sprite, mut flash) in &mut q_flashes {
+        if flash.lifetime.tick(time.delta()).just_finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+        sprite.color.set_a(0.5 * flash.lifetime.percent_left());
+    }
+}
+
+fn swap_event_update_grid(
+    mut grid: ResMut<Grid>,
+    mut ev_swap: EventReader<SwapEvent>,
+    mut ev_recalculate: EventWriter<RecalculateFlowFieldEvent>,
+) {
+    let mut swapped = false;
     for ev in ev_swap.iter() {
         grid.swap(ev.from, ev.to);
+        swapped = true;
+    }
+    // the tiles took their terrain and structures with them
+    if swapped {
+        ev_recalculate.send(RecalculateFlowFieldEvent);
     }
 }
 
@@ -142,8 +562,10 @@ fn swap_event_update_tile_coords(
 }
 
 fn swap_event_update_drag_home(
-    mut q_draggable: Query<(&mut Transform, &mut Draggable)>,
+    mut commands: Commands,
+    mut q_draggable: Query<(&Transform, &mut Draggable)>,
     mut ev_swap: EventReader<SwapEvent>,
+    animation: Res<SwapAnimation>,
 ) {
     for ev in ev_swap.iter() {
         if let Ok(mut v) = q_draggable.get_many_mut([ev.from_ent, ev.to_ent]) {
@@ -151,7 +573,20 @@ fn swap_event_update_drag_home(
             v[0].1.home = v[1].1.home;
             v[1].1.home = temp;
 
-            v[1].0.translation = v[1].1.home;
+            // the one that wasn't dragged slides over to its new home.
+            // nothing is dragged when undoing so both do
+            commands.entity(ev.to_ent).insert(SwapTween::new(
+                v[1].0.translation,
+                v[1].1.home,
+                &animation,
+            ));
+            if ev.replay {
+                commands.entity(ev.from_ent).insert(SwapTween::new(
+                    v[0].0.translation,
+                    v[0].1.home,
+                    &animation,
+                ));
+            }
         }
     }
 }
@@ -160,15 +595,68 @@ fn swap_event_combine_resources(
     mut q_resource: Query<&mut Resource>,
     mut ev_swap: EventReader<SwapEvent>,
 ) {
-    for ev in ev_swap.iter() {
+    for ev in ev_swap.iter().filter(|ev| !ev.replay) {
         if let Ok(mut v) = q_resource.get_many_mut([ev.from_ent, ev.to_ent]) {
-            match (*v[0], *v[1]) {
-                (Resource::Gold(a), Resource::Gold(b)) => {
-                    *v[0] = Resource::Gold(a + b);
-                    *v[1] = Resource::None;
-                }
-                _ => {}
+            let [from, to] = combine_resources([*v[0], *v[1]]);
+            // read first so tiles with nothing to join aren't marked as changed
+            if from != *v[0] {
+                *v[0] = from;
+                *v[1] = to;
             }
         }
     }
 }
+
+/// What the dragged and other tile hold after swapping.
+/// Stacks of the same kind join up on the dragged tile.
+pub fn combine_resources([mut from, mut to]: [Resource; 2]) -> [Resource; 2] {
+    for kind in ResourceKind::ALL {
+        if from.get(kind) > 0 && to.get(kind) > 0 {
+            let moved = to.take(kind, u32::MAX);
+            from.add(kind, moved);
+        }
+    }
+    [from, to]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: GridLayout = GridLayout {
+        width: 3,
+        height: 3,
+        tile_size: 32.0,
+    };
+
+    fn costs_with_walls(walls: &[(usize, usize)]) -> Vec<u8> {
+        let mut costs = vec![1; LAYOUT.tile_count()];
+        for &(x, y) in walls {
+            costs[LAYOUT.index(x, y)] = STRUCTURE_COST;
+        }
+        costs
+    }
+
+    #[test]
+    fn open_grid_has_nothing_cut_off() {
+        let costs = costs_with_walls(&[]);
+        assert_eq!(cut_off_tiles(&LAYOUT, &costs, Coords { x: 0, y: 0 }), 0);
+    }
+
+    #[test]
+    fn wall_across_the_grid_cuts_off_the_far_side() {
+        let costs = costs_with_walls(&[(1, 0), (1, 1), (1, 2)]);
+        assert_eq!(cut_off_tiles(&LAYOUT, &costs, Coords { x: 0, y: 0 }), 3);
+
+        // a gap lets everything through again
+        let costs = costs_with_walls(&[(1, 0), (1, 1)]);
+        assert_eq!(cut_off_tiles(&LAYOUT, &costs, Coords { x: 0, y: 0 }), 0);
+    }
+
+    #[test]
+    fn structures_themselves_dont_count() {
+        let costs = costs_with_walls(&[(1, 0), (0, 1)]);
+        // only the walled in corner is open and unreachable
+        assert_eq!(cut_off_tiles(&LAYOUT, &costs, Coords { x: 2, y: 2 }), 1);
+    }
+}
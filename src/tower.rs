@@ -1,10 +1,21 @@
 use bevy::prelude::*;
 
 use crate::{
-    gold::GoldSpawner,
-    grid::{ClearSelectionsEvent, Selection, TerritoryGrabber, Tile},
-    gun::{BasicGunInfo, Bullet, BurstInfo, EndBehaviour, ExplosionInfo, Gun, GunType},
+    enemy::{Enemy, TargetLayers},
+    gold::{Bank, Generator, Treasury},
+    grid::{
+        ClearSelectionsEvent, Grid, GridLayout, RemoveStructureEvent, Selection,
+        StructureDestroyedEvent, TerritoryGrabber, Tile, TileType, STRUCTURE_COST,
+    },
+    gun::{
+        AimMode, AmmoCost, BasicGunInfo, BeamInfo, Bullet, BurstInfo, ChainInfo, DamageFalloff,
+        EndBehaviour, ExplosionInfo, Gun, GunType, HitscanInfo, SplitInfo, SpreadInfo,
+    },
+    health::{Health, HealthBar},
+    history::{GridAction, RecordActionEvent},
     loading::FontAssets,
+    resource_container::{Resource, ResourceKind},
+    spatial::EnemySpatialIndex,
     GameState, MouseWorldPos,
 };
 
@@ -16,45 +27,114 @@ impl Plugin for TowerPlugin {
             SystemSet::on_exit(GameState::Loading).with_system(create_tower_store_ui),
         );
         app.add_event::<BuildButtonEvent>()
+            .add_event::<PlaceTowerEvent>()
             .add_system(tower_build_buttons_interactions.before(build_tower_system))
-            .add_system(build_tower_system.before(crate::grid::clear_selection))
-            .add_system(tower_shoot);
+            .add_system(
+                build_tower_system
+                    .before(crate::grid::clear_selection)
+                    .before(place_towers),
+            )
+            .add_system(place_towers)
+            .add_system(sell_towers.before(crate::grid::clear_selection))
+            .add_system(upgrade_towers.before(crate::grid::clear_selection))
+            .add_system(tower_shoot)
+            .add_system(supply_ammo)
+            .add_system(show_out_of_ammo)
+            .add_system(destroy_tower);
     }
 }
 
 fn build_tower_system(
-    mut commands: Commands,
-    mut q_selection: Query<(Entity, &mut Tile), With<Selection>>,
+    q_selection: Query<(Entity, &Tile), With<Selection>>,
     mut ev_clear: EventWriter<ClearSelectionsEvent>,
     mut ev_build: EventReader<BuildButtonEvent>,
+    mut ev_place: EventWriter<PlaceTowerEvent>,
+    mut ev_record: EventWriter<RecordActionEvent>,
+    mut treasury: ResMut<Treasury>,
 ) {
     for ev in ev_build.iter() {
         ev_clear.send(ClearSelectionsEvent);
-        for (tile_ent, mut tile) in q_selection.iter_mut() {
-            tile.cost = 200; // don't walk over towers
+        let cost = ev.tower_type.cost();
+        for (tile_ent, tile) in q_selection.iter() {
+            // already has a wall or tower, or the ground won't take one
+            if !tile.can_build() {
+                continue;
+            }
+            if !treasury.stock.pay(&cost) {
+                println!("Can't afford a {:?}. Costs {}", ev.tower_type, cost);
+                break;
+            }
+            ev_place.send(PlaceTowerEvent {
+                tile: tile_ent,
+                tower_type: ev.tower_type,
+                level: 0,
+            });
+            ev_record.send(RecordActionEvent(GridAction::Build {
+                tile: tile_ent,
+                tower_type: ev.tower_type,
+                cost,
+                permanent: false,
+            }));
+        }
+    }
+}
+
+/// Puts a tower on `tile`. Already paid for.
+pub struct PlaceTowerEvent {
+    pub tile: Entity,
+    pub tower_type: TowerType,
+    // upgrades it already has, when a sold tower comes back
+    pub level: u32,
+}
+
+fn place_towers(
+    mut commands: Commands,
+    mut ev_place: EventReader<PlaceTowerEvent>,
+    mut q_tiles: Query<&mut Tile>,
+    layout: Res<GridLayout>,
+) {
+    for ev in ev_place.iter() {
+        let tile_ent = ev.tile;
+        let gun_at_level = |gun_info: BasicGunInfo| {
+            let mut gun = Gun::from_basic_gun_info(gun_info);
+            for _ in 0..ev.level {
+                gun.upgrade_damage();
+            }
+            gun
+        };
+        if let Ok(mut tile) = q_tiles.get_mut(tile_ent) {
+            tile.cost = STRUCTURE_COST; // don't walk over towers
+            commands
+                .entity(tile_ent)
+                .insert(TowerComponent::new(ev.tower_type, ev.level))
+                .insert(Health::new(20))
+                .insert(HealthBar::for_tile(layout.tile_size));
 
             match ev.tower_type {
                 TowerType::Pistol => {
                     commands
                         .entity(tile_ent)
-                        .insert(TowerComponent {})
-                        .insert(Gun::from_basic_gun_info(BasicGunInfo::default()))
+                        .insert(gun_at_level(BasicGunInfo {
+                            // anti-air
+                            targets: TargetLayers::ALL,
+                            ..default()
+                        }))
                         .insert(TerritoryGrabber::new(4));
                 }
                 TowerType::Shotgun => {
                     commands
                         .entity(tile_ent)
-                        .insert(TowerComponent {})
-                        .insert(Gun::from_basic_gun_info(BasicGunInfo {
+                        .insert(gun_at_level(BasicGunInfo {
                             bullet: Bullet::new(
                                 1,
                                 EndBehaviour::Explode(ExplosionInfo::new(30.0, 5)),
-                            ),
+                            )
+                            .with_falloff(DamageFalloff::new(40.0, 120.0, 1)),
                             // can change these fields from the default
                             // time_between_shots: 0.3,
                             // clip_size: 6,
                             // time_to_reload: 1.0,
-                            gun_type: GunType::Shotgun,
+                            gun_type: GunType::Shotgun(SpreadInfo::new(2, 20.0, 0.0)),
                             ..default()
                         }))
                         .insert(TerritoryGrabber::new(2));
@@ -62,10 +142,10 @@ fn build_tower_system(
                 TowerType::Burst => {
                     commands
                         .entity(tile_ent)
-                        .insert(TowerComponent {})
-                        .insert(Gun::from_basic_gun_info(BasicGunInfo {
-                            bullet: Bullet::new(1, EndBehaviour::Split(2)),
+                        .insert(gun_at_level(BasicGunInfo {
+                            bullet: Bullet::new(1, EndBehaviour::Split(SplitInfo::new(2, 10.0))),
                             gun_type: GunType::Burst(BurstInfo::from(0.1, 3)),
+                            targets: TargetLayers::ALL,
                             ..default()
                         }))
                         .insert(TerritoryGrabber::new(3));
@@ -73,13 +153,62 @@ fn build_tower_system(
                 TowerType::Bomb => {
                     commands
                         .entity(tile_ent)
-                        .insert(TowerComponent {})
-                        .insert(Gun::from_basic_gun_info(BasicGunInfo {
+                        .insert(gun_at_level(BasicGunInfo {
                             bullet: Bullet::new_arc(
                                 0,
                                 EndBehaviour::Explode(ExplosionInfo::new(30.0, 5)),
                             ),
                             gun_type: GunType::Bomb,
+                            // bombs are slow. Need to lead
+                            aim: AimMode::Lead,
+                            ammo: Some(AmmoCost {
+                                kind: ResourceKind::Stone,
+                                per_reload: 2,
+                            }),
+                            ..default()
+                        }))
+                        .insert(TerritoryGrabber::new(3));
+                }
+                TowerType::Sniper => {
+                    commands
+                        .entity(tile_ent)
+                        .insert(gun_at_level(BasicGunInfo {
+                            bullet: Bullet::new(5, EndBehaviour::None),
+                            time_between_shots: 1.5,
+                            clip_size: 4,
+                            time_to_reload: 3.0,
+                            gun_type: GunType::Hitscan(HitscanInfo::new(600.0)),
+                            targets: TargetLayers::ALL,
+                            ..default()
+                        }))
+                        .insert(TerritoryGrabber::new(2));
+                }
+                TowerType::Beam => {
+                    commands
+                        .entity(tile_ent)
+                        .insert(gun_at_level(BasicGunInfo {
+                            bullet: Bullet::new(1, EndBehaviour::None),
+                            // ticks fast enough to look continuous
+                            time_between_shots: 0.1,
+                            clip_size: 30,
+                            time_to_reload: 2.0,
+                            gun_type: GunType::Beam(BeamInfo::new(150.0, 2.0, 4)),
+                            ammo: Some(AmmoCost {
+                                kind: ResourceKind::Mana,
+                                per_reload: 1,
+                            }),
+                            ..default()
+                        }))
+                        .insert(TerritoryGrabber::new(2));
+                }
+                TowerType::Chain => {
+                    commands
+                        .entity(tile_ent)
+                        .insert(gun_at_level(BasicGunInfo {
+                            bullet: Bullet::new(2, EndBehaviour::None),
+                            time_between_shots: 0.8,
+                            gun_type: GunType::Chain(ChainInfo::new(200.0, 4, 64.0)),
+                            targets: TargetLayers::ALL,
                             ..default()
                         }))
                         .insert(TerritoryGrabber::new(3));
@@ -87,8 +216,20 @@ fn build_tower_system(
                 TowerType::NoGun => {
                     commands
                         .entity(tile_ent)
-                        .insert(TowerComponent {})
-                        .insert(GoldSpawner::new());
+                        .insert(Generator::new(ResourceKind::Gold));
+                }
+                TowerType::Quarry => {
+                    commands
+                        .entity(tile_ent)
+                        .insert(Generator::new(ResourceKind::Stone));
+                }
+                TowerType::ManaWell => {
+                    commands
+                        .entity(tile_ent)
+                        .insert(Generator::new(ResourceKind::Mana));
+                }
+                TowerType::Bank => {
+                    commands.entity(tile_ent).insert(Bank);
                 }
             }
         }
@@ -98,11 +239,152 @@ fn build_tower_system(
 fn tower_shoot(
     mut commands: Commands,
     mut q_towers: Query<(Entity, &Transform, &TowerComponent, &mut Gun)>,
+    q_enemies: Query<&Enemy>,
+    index: Res<EnemySpatialIndex>,
     mouse: Res<MouseWorldPos>,
+    grid: Res<Grid>,
 ) {
+    // leading guns lock on to the enemy under the mouse
+    let target = index
+        .nearest_enemy(mouse.0, grid.layout().tile_size, TargetLayers::ALL, |_| {
+            true
+        })
+        .and_then(|(entity, pos)| {
+            q_enemies
+                .get(entity)
+                .ok()
+                .map(|enemy| (pos, enemy.velocity()))
+        });
+
     for (entity, trans, _tower, mut gun) in q_towers.iter_mut() {
         // let dir = mouse.0 - trans.translation.truncate();
-        gun.shoot(&mut commands, trans.translation, mouse.0, entity);
+        let target_pos = match (gun.aim_mode(), target) {
+            (AimMode::Lead, Some((enemy_pos, enemy_vel))) => {
+                gun.lead_target(trans.translation.truncate(), enemy_pos, enemy_vel)
+            }
+            _ => mouse.0,
+        };
+        gun.shoot(&mut commands, trans.translation, target_pos, entity);
+    }
+}
+
+/// Feeds guns waiting on ammo from their own tile, then friendly tiles next to it.
+fn supply_ammo(
+    mut q_guns: Query<(Entity, &Tile, &mut Gun)>,
+    mut q_tiles: Query<(&Tile, &mut Resource)>,
+    grid: Res<Grid>,
+) {
+    for (entity, tile, mut gun) in &mut q_guns {
+        let ammo = match gun.ammo_needed() {
+            Some(ammo) => ammo,
+            None => continue,
+        };
+
+        let mut sources = vec![entity];
+        sources.extend(grid.get_diamond_ring(tile.coords, 1).into_iter().flatten());
+        let can_use = |source: Entity, source_tile: &Tile| {
+            source == entity || matches!(source_tile.tile_type, TileType::Friendly)
+        };
+
+        // only take anything if there's enough for a full reload
+        let available: u32 = sources
+            .iter()
+            .filter_map(|&source| q_tiles.get(source).ok().map(|t| (source, t)))
+            .filter(|(source, (source_tile, _))| can_use(*source, source_tile))
+            .map(|(_, (_, res))| res.get(ammo.kind))
+            .sum();
+        if available < ammo.per_reload {
+            continue;
+        }
+
+        let mut remaining = ammo.per_reload;
+        for source in sources {
+            if remaining == 0 {
+                break;
+            }
+            if let Ok((source_tile, mut res)) = q_tiles.get_mut(source) {
+                if can_use(source, source_tile) && res.get(ammo.kind) > 0 {
+                    remaining -= res.take(ammo.kind, remaining);
+                }
+            }
+        }
+        gun.load_ammo();
+    }
+}
+
+#[derive(Component)]
+struct OutOfAmmoIcon;
+
+fn show_out_of_ammo(
+    mut commands: Commands,
+    q_guns: Query<(Entity, &Gun, Option<&Children>)>,
+    mut q_icons: Query<&mut Visibility, With<OutOfAmmoIcon>>,
+    layout: Res<GridLayout>,
+) {
+    for (entity, gun, children) in &q_guns {
+        if !gun.uses_ammo() {
+            continue;
+        }
+        let out = gun.is_out_of_ammo();
+        let icon = children.and_then(|children| {
+            children
+                .iter()
+                .find(|&&child| q_icons.contains(child))
+                .copied()
+        });
+
+        match icon {
+            Some(icon) => {
+                if let Ok(mut visibility) = q_icons.get_mut(icon) {
+                    if visibility.is_visible != out {
+                        visibility.is_visible = out;
+                    }
+                }
+            }
+            None if out => {
+                // red square in the top right corner of the tile
+                let icon = commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::RED,
+                            custom_size: Some(Vec2::splat(layout.tile_size * 0.25)),
+                            ..default()
+                        },
+                        transform: Transform::from_translation(
+                            Vec2::splat(layout.tile_size * 0.3125).extend(0.5),
+                        ),
+                        ..default()
+                    })
+                    .insert(OutOfAmmoIcon)
+                    .id();
+                commands.entity(entity).add_child(icon);
+            }
+            None => {}
+        }
+    }
+}
+
+fn destroy_tower(
+    mut commands: Commands,
+    mut ev_destroyed: EventReader<StructureDestroyedEvent>,
+    q_children: Query<&Children>,
+    q_icons: Query<Entity, With<OutOfAmmoIcon>>,
+) {
+    for ev in ev_destroyed.iter() {
+        if let Ok(children) = q_children.get(ev.tile) {
+            for &child in children.iter() {
+                if q_icons.contains(child) {
+                    commands.entity(child).despawn_recursive();
+                }
+            }
+        }
+        commands
+            .entity(ev.tile)
+            .remove::<TowerComponent>()
+            .remove::<Gun>()
+            .remove::<TerritoryGrabber>()
+            .remove::<Generator>()
+            .remove::<Bank>();
     }
 }
 
@@ -110,6 +392,60 @@ fn tower_shoot(
 struct TowerComponent {
     // gun: Gun,
     // brain: Box<dyn TowerBrain>,
+    tower_type: TowerType,
+    level: u32,
+}
+
+impl TowerComponent {
+    fn new(tower_type: TowerType, level: u32) -> Self {
+        TowerComponent { tower_type, level }
+    }
+}
+
+fn sell_towers(
+    q_selection: Query<(Entity, &TowerComponent), With<Selection>>,
+    keyboard: Res<Input<KeyCode>>,
+    mut ev_clear: EventWriter<ClearSelectionsEvent>,
+    mut ev_remove: EventWriter<RemoveStructureEvent>,
+    mut ev_record: EventWriter<RecordActionEvent>,
+    mut treasury: ResMut<Treasury>,
+) {
+    if keyboard.just_pressed(KeyCode::X) {
+        ev_clear.send(ClearSelectionsEvent);
+        for (tile_ent, tower) in q_selection.iter() {
+            let refund = tower.tower_type.sell_value();
+            for (kind, amount) in refund.iter() {
+                treasury.stock.add(kind, amount);
+            }
+            ev_remove.send(RemoveStructureEvent { tile: tile_ent });
+            ev_record.send(RecordActionEvent(GridAction::Sell {
+                tile: tile_ent,
+                tower_type: tower.tower_type,
+                level: tower.level,
+                refund,
+            }));
+        }
+    }
+}
+
+fn upgrade_towers(
+    mut q_selection: Query<(&mut TowerComponent, &mut Gun), With<Selection>>,
+    keyboard: Res<Input<KeyCode>>,
+    mut ev_clear: EventWriter<ClearSelectionsEvent>,
+    mut treasury: ResMut<Treasury>,
+) {
+    if keyboard.just_pressed(KeyCode::R) {
+        ev_clear.send(ClearSelectionsEvent);
+        for (mut tower, mut gun) in q_selection.iter_mut() {
+            let cost = tower.tower_type.upgrade_cost(tower.level);
+            if !treasury.stock.pay(&cost) {
+                println!("Can't afford to upgrade. Costs {}", cost);
+                break;
+            }
+            tower.level += 1;
+            gun.upgrade_damage();
+        }
+    }
 }
 
 // Maybe bundles is the way to go?
@@ -125,12 +461,56 @@ struct TowerBuildButton {
 }
 
 #[derive(Debug, Copy, Clone)]
-enum TowerType {
+pub enum TowerType {
     Pistol,
     Shotgun,
     Burst,
     Bomb,
+    Sniper,
+    Beam,
+    Chain,
     NoGun,
+    Quarry,
+    ManaWell,
+    Bank,
+}
+
+impl TowerType {
+    fn cost(&self) -> Resource {
+        use ResourceKind::*;
+        match self {
+            TowerType::Pistol => Resource::of(Gold, 5),
+            TowerType::Shotgun => Resource::of(Gold, 6).with(Stone, 2),
+            TowerType::Burst => Resource::of(Gold, 6).with(Mana, 2),
+            TowerType::Bomb => Resource::of(Gold, 8).with(Stone, 4),
+            TowerType::Sniper => Resource::of(Gold, 8).with(Stone, 2).with(Mana, 2),
+            TowerType::Beam => Resource::of(Gold, 6).with(Mana, 4),
+            TowerType::Chain => Resource::of(Gold, 8).with(Mana, 4),
+            TowerType::NoGun => Resource::of(Gold, 10),
+            TowerType::Quarry => Resource::of(Gold, 10),
+            TowerType::ManaWell => Resource::of(Gold, 8).with(Stone, 4),
+            TowerType::Bank => Resource::of(Gold, 10).with(Stone, 5),
+        }
+    }
+
+    /// Half the cost back, rounded down. Upgrades aren't refunded.
+    fn sell_value(&self) -> Resource {
+        let cost = self.cost();
+        ResourceKind::ALL
+            .into_iter()
+            .fold(Resource::default(), |refund, kind| {
+                refund.with(kind, cost.get(kind) / 2)
+            })
+    }
+
+    /// Each level costs more than the last.
+    fn upgrade_cost(&self, level: u32) -> Resource {
+        use ResourceKind::*;
+        let next = level + 1;
+        Resource::of(Gold, 4 * next)
+            .with(Stone, 2 * next)
+            .with(Mana, level)
+    }
 }
 struct BuildButtonEvent {
     tower_type: TowerType,
@@ -167,7 +547,13 @@ fn create_tower_store_ui(mut commands: Commands, fonts: Res<FontAssets>) {
                 TowerType::Shotgun,
                 TowerType::Burst,
                 TowerType::Bomb,
+                TowerType::Sniper,
+                TowerType::Beam,
+                TowerType::Chain,
                 TowerType::NoGun,
+                TowerType::Quarry,
+                TowerType::ManaWell,
+                TowerType::Bank,
             ];
             for t in arr.iter() {
                 // let tower_type = TowerType::Pistol;
@@ -199,7 +585,7 @@ fn create_tower_store_ui(mut commands: Commands, fonts: Res<FontAssets>) {
                 .insert(TowerBuildButton { tower_type: *t })
                 .with_children(|button_base| {
                     button_base.spawn_bundle(TextBundle::from_section(
-                        format!("{:?}", t),
+                        format!("{:?}\n{}", t, t.cost()),
                         TextStyle {
                             font: fonts.fira_sans.clone(),
                             font_size: 20.0,
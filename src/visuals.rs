@@ -0,0 +1,67 @@
+use bevy::{prelude::*, sprite::Mesh2dHandle, utils::HashMap};
+
+use crate::resource_container::ResourceKind;
+
+pub struct VisualsPlugin;
+
+impl Plugin for VisualsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(VisualsCache::default())
+            .add_startup_system(build_visuals_cache);
+    }
+}
+
+// sizes and colours used all the time
+pub const COIN_RADIUS: f32 = 6.0;
+pub const COIN_POP_RADIUS: f32 = 12.0;
+pub const EXPLOSION_COLOUR: Color = Color::ORANGE;
+pub const HAZARD_COLOUR: Color = Color::rgba(0.5, 0.9, 0.1, 0.3);
+
+/// Shared mesh and material handles so the same circle or colour is only made once.
+/// Sharing handles also lets them be batched.
+///
+/// Common ones are made at startup. Anything else is made the first time it's asked for.
+#[derive(Default)]
+pub struct VisualsCache {
+    // keyed by radius bits
+    circles: HashMap<u32, Mesh2dHandle>,
+    // keyed by rgba bits
+    materials: HashMap<[u32; 4], Handle<ColorMaterial>>,
+}
+
+impl VisualsCache {
+    pub fn circle(&mut self, meshes: &mut Assets<Mesh>, radius: f32) -> Mesh2dHandle {
+        self.circles
+            .entry(radius.to_bits())
+            .or_insert_with(|| meshes.add(shape::Circle::new(radius).into()).into())
+            .clone()
+    }
+
+    pub fn material(
+        &mut self,
+        materials: &mut Assets<ColorMaterial>,
+        colour: Color,
+    ) -> Handle<ColorMaterial> {
+        let key = colour.as_rgba_f32().map(f32::to_bits);
+        self.materials
+            .entry(key)
+            .or_insert_with(|| materials.add(ColorMaterial::from(colour)))
+            .clone()
+    }
+}
+
+fn build_visuals_cache(
+    mut cache: ResMut<VisualsCache>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for radius in [COIN_RADIUS, COIN_POP_RADIUS] {
+        cache.circle(&mut meshes, radius);
+    }
+    for kind in ResourceKind::ALL {
+        cache.material(&mut materials, kind.colour());
+    }
+    for colour in [EXPLOSION_COLOUR, HAZARD_COLOUR] {
+        cache.material(&mut materials, colour);
+    }
+}
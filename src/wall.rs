@@ -1,41 +1,102 @@
 use bevy::prelude::*;
 
-use crate::grid::{ClearSelectionsEvent, Selection, Tile};
+use crate::{
+    grid::{
+        ClearSelectionsEvent, GridLayout, Selection, StructureDestroyedEvent, Tile, STRUCTURE_COST,
+    },
+    health::{Health, HealthBar},
+    history::{GridAction, RecordActionEvent},
+};
 
 pub struct WallPlugin;
 
 impl Plugin for WallPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(spawn_wall);
+        app.add_event::<PlaceWallEvent>()
+            .add_system(spawn_wall.before(place_walls))
+            .add_system(place_walls)
+            .add_system(destroy_wall);
     }
 }
 
 #[derive(Component)]
 struct Wall;
 
+/// Puts a wall on `tile`. Sent by the player and by redo.
+pub struct PlaceWallEvent {
+    pub tile: Entity,
+}
+
 fn spawn_wall(
-    mut commands: Commands,
-    mut q_selection: Query<(Entity, &mut Tile), With<Selection>>,
+    q_selection: Query<(Entity, &Tile), With<Selection>>,
     keyboard: Res<Input<KeyCode>>,
     mut ev_clear: EventWriter<ClearSelectionsEvent>,
+    mut ev_place: EventWriter<PlaceWallEvent>,
+    mut ev_record: EventWriter<RecordActionEvent>,
 ) {
     if keyboard.just_pressed(KeyCode::W) {
         ev_clear.send(ClearSelectionsEvent);
-        for (ent, mut tile) in q_selection.iter_mut() {
-            tile.cost = 200;
-            commands.entity(ent).with_children(|commands| {
-                commands
-                    .spawn_bundle(SpriteBundle {
-                        sprite: Sprite {
-                            color: Color::BEIGE,
-                            custom_size: Some(Vec2::splat(26.0)),
-                            ..default()
-                        },
-                        transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.1)),
-                        ..default()
-                    })
-                    .insert(Wall);
-            });
+        for (ent, tile) in q_selection.iter() {
+            // already has a wall or tower, or the ground won't take one
+            if !tile.can_build() {
+                continue;
+            }
+            ev_place.send(PlaceWallEvent { tile: ent });
+            ev_record.send(RecordActionEvent(GridAction::Wall { tile: ent }));
+        }
+    }
+}
+
+fn place_walls(
+    mut commands: Commands,
+    mut ev_place: EventReader<PlaceWallEvent>,
+    mut q_tiles: Query<&mut Tile>,
+    layout: Res<GridLayout>,
+) {
+    for ev in ev_place.iter() {
+        let mut tile = match q_tiles.get_mut(ev.tile) {
+            Ok(tile) => tile,
+            Err(_) => continue,
+        };
+        add_wall(&mut commands, ev.tile, &mut tile, layout.tile_size);
+    }
+}
+
+/// Makes `tile` a wall. For tiles that might not be spawned yet, like when a level is loading.
+pub fn add_wall(commands: &mut Commands, tile_ent: Entity, tile: &mut Tile, tile_size: f32) {
+    tile.cost = STRUCTURE_COST;
+    commands
+        .entity(tile_ent)
+        .insert(Health::new(10))
+        .insert(HealthBar::for_tile(tile_size));
+    commands.entity(tile_ent).with_children(|commands| {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::BEIGE,
+                    custom_size: Some(Vec2::splat(tile_size * 0.8125)),
+                    ..default()
+                },
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.1)),
+                ..default()
+            })
+            .insert(Wall);
+    });
+}
+
+fn destroy_wall(
+    mut commands: Commands,
+    mut ev_destroyed: EventReader<StructureDestroyedEvent>,
+    q_children: Query<&Children>,
+    q_walls: Query<Entity, With<Wall>>,
+) {
+    for ev in ev_destroyed.iter() {
+        if let Ok(children) = q_children.get(ev.tile) {
+            for &child in children.iter() {
+                if q_walls.contains(child) {
+                    commands.entity(child).despawn_recursive();
+                }
+            }
         }
     }
 }